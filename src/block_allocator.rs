@@ -1,16 +1,51 @@
 use thiserror::Error;
 
+use crate::utils::bp_tree::BPTreeError;
+
 #[derive(Error, Debug)]
 pub enum BlockAllocateError {
     #[error("Index out of range")]
     IdxOutOfRange,
     #[error("There have no free disk blocks")]
     NoFreeBlocks,
+    #[error("Invalid extent length: min {min_len}, max {max_len}")]
+    InvalidExtentLength { min_len: u64, max_len: u64 },
+    #[error("Block {0} is already free")]
+    DoubleFree(u64),
+    #[error("Free-space tree error: {0}")]
+    TreeError(Box<BPTreeError>),
+}
+
+/// How `alloc_extent` picks among the free extents that are long enough.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ExtentPolicy {
+    /// The lowest-addressed extent holding at least `min_len` blocks.
+    ///
+    /// The search resumes past the extents an earlier one for as many blocks or fewer found
+    /// too short, so repeated allocations do not walk the same head of the tree again.
+    #[default]
+    FirstFit,
+    /// The smallest extent holding `max_len` blocks, or the largest one holding `min_len`.
+    ///
+    /// Unless an extent of exactly `max_len` blocks turns up, this reads every free extent
+    /// past the first that fits, which costs a walk over most of the free-space tree on each
+    /// allocation.
+    BestFit,
 }
 
 pub trait BlockAllocator {
     fn alloc(&mut self) -> Result<u64, BlockAllocateError>;
     fn free(&mut self, idx: u64) -> Result<(), BlockAllocateError>;
+    /// Allocates between `min_len` and `max_len` contiguous blocks, returning `(start, len)`.
+    fn alloc_extent(
+        &mut self,
+        min_len: u64,
+        max_len: u64,
+    ) -> Result<(u64, u64), BlockAllocateError>;
+    /// Allocates the free block closest at or after `goal`, wrapping around when needed.
+    fn alloc_near(&mut self, _goal: u64) -> Result<u64, BlockAllocateError> {
+        self.alloc()
+    }
 }
 
 pub mod bptree_allocator;
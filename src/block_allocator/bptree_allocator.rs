@@ -3,7 +3,7 @@ use std::{cell::RefCell, rc::Rc};
 use super::BlockAllocator;
 use crate::{
     IOContext,
-    block_allocator::{BlockAllocateError, none_allocator::NoneAllocator},
+    block_allocator::{BlockAllocateError, ExtentPolicy, none_allocator::NoneAllocator},
     block_device::BlockDevice,
     utils::{
         bp_tree::{BPTree, BPTreeError},
@@ -18,6 +18,8 @@ where
     A: BlockAllocator,
 {
     bptree: BPTree<D, C, A>,
+    blocks_count: u64,
+    policy: ExtentPolicy,
 }
 
 impl<D, C> BPTreeAllocator<D, C, NoneAllocator>
@@ -25,11 +27,51 @@ where
     D: BlockDevice,
     C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
 {
-    pub fn try_new(ioc: Rc<RefCell<IOContext<D, C>>>, beg_block: u64) -> Result<Self, BPTreeError> {
+    /// Formats a new free-space tree with its descriptor in `desc_block` and its root right
+    /// after it, marking every later block as free.
+    pub fn create(ioc: Rc<RefCell<IOContext<D, C>>>, desc_block: u64) -> Result<Self, BPTreeError> {
+        let blocks_count = Self::blocks_count(&ioc);
         Ok(Self {
-            bptree: BPTree::new_as_block_manager(ioc, beg_block)?,
+            bptree: BPTree::create_block_manager(ioc, desc_block)?,
+            blocks_count,
+            policy: ExtentPolicy::default(),
         })
     }
+
+    /// Reopens the free-space tree whose descriptor lives in `desc_block`.
+    pub fn open(ioc: Rc<RefCell<IOContext<D, C>>>, desc_block: u64) -> Result<Self, BPTreeError> {
+        let blocks_count = Self::blocks_count(&ioc);
+        Ok(Self {
+            bptree: BPTree::open_block_manager(ioc, desc_block)?,
+            blocks_count,
+            policy: ExtentPolicy::default(),
+        })
+    }
+
+    pub fn set_policy(&mut self, policy: ExtentPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn get_m(&self) -> u64 {
+        self.bptree.get_m()
+    }
+
+    /// Number of free blocks, kept up to date by the free-space tree.
+    pub fn free_blocks_count(&self) -> u64 {
+        self.bptree.free_blocks()
+    }
+
+    fn blocks_count(ioc: &Rc<RefCell<IOContext<D, C>>>) -> u64 {
+        let ioc = ioc.borrow();
+        ioc.get_disk_capacity() / ioc.get_disk_block_size()
+    }
+}
+
+fn tree_error(e: BPTreeError) -> BlockAllocateError {
+    match e {
+        BPTreeError::AllocateError(e) => e,
+        e => BlockAllocateError::TreeError(Box::new(e)),
+    }
 }
 
 impl<D, C> BlockAllocator for BPTreeAllocator<D, C, NoneAllocator>
@@ -38,12 +80,182 @@ where
     C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
 {
     fn alloc(&mut self) -> Result<u64, BlockAllocateError> {
+        self.bptree.pop_first_extent().map_err(tree_error)
+    }
+
+    fn alloc_extent(
+        &mut self,
+        min_len: u64,
+        max_len: u64,
+    ) -> Result<(u64, u64), BlockAllocateError> {
         self.bptree
-            .pop_first_extent()
-            .map_err(|_| BlockAllocateError::NoFreeBlocks)
+            .alloc_extent(min_len, max_len, self.policy)
+            .map_err(tree_error)
+    }
+
+    fn alloc_near(&mut self, goal: u64) -> Result<u64, BlockAllocateError> {
+        if goal >= self.blocks_count {
+            return Err(BlockAllocateError::IdxOutOfRange);
+        }
+        self.bptree.alloc_near(goal).map_err(tree_error)
     }
 
     fn free(&mut self, idx: u64) -> Result<(), super::BlockAllocateError> {
-        todo!()
+        if idx >= self.blocks_count {
+            return Err(BlockAllocateError::IdxOutOfRange);
+        }
+        self.bptree.insert_extent(idx, 1).map_err(|e| match e {
+            BPTreeError::ExtentOverlap { .. } => BlockAllocateError::DoubleFree(idx),
+            e => tree_error(e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::block_allocator::test_allocator::{TestBPTreeAllocator, new_bptree_allocator};
+
+    use super::*;
+
+    #[test]
+    fn free_coalesces() -> Result<(), BlockAllocateError> {
+        let (_, mut allocator) = new_bptree_allocator(4096).unwrap();
+
+        let mut blocks = Vec::new();
+        while let Ok(idx) = allocator.alloc() {
+            blocks.push(idx);
+        }
+        assert_eq!(blocks.len(), 4094);
+
+        for &idx in blocks.iter().step_by(2) {
+            allocator.free(idx)?;
+        }
+        for &idx in blocks.iter().skip(1).step_by(2) {
+            allocator.free(idx)?;
+        }
+
+        let mut seen = HashSet::new();
+        while let Ok(idx) = allocator.alloc() {
+            assert!(seen.insert(idx));
+        }
+        assert_eq!(seen.len(), 4094);
+        Ok(())
+    }
+
+    #[test]
+    fn alloc_extent_policies() -> Result<(), BlockAllocateError> {
+        let (_, mut allocator) = new_bptree_allocator(1024).unwrap();
+
+        // Leave free holes of 8, 2 and 4 blocks in front of the tail.
+        let (start, len) = allocator.alloc_extent(20, 20)?;
+        assert_eq!((start, len), (2, 20));
+        for idx in (3..11).chain(13..15).chain(17..21) {
+            allocator.free(idx)?;
+        }
+
+        assert_eq!(allocator.alloc_extent(2, 3)?, (3, 3));
+        allocator.set_policy(ExtentPolicy::BestFit);
+        assert_eq!(allocator.alloc_extent(2, 2)?, (13, 2));
+        assert_eq!(allocator.alloc_extent(2, 4)?, (17, 4));
+        assert_eq!(allocator.alloc_extent(1, 64)?, (22, 64));
+        assert!(matches!(
+            allocator.alloc_extent(4, 2),
+            Err(BlockAllocateError::InvalidExtentLength { .. })
+        ));
+        assert!(matches!(
+            allocator.alloc_extent(2048, 2048),
+            Err(BlockAllocateError::NoFreeBlocks)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn first_fit_after_free() -> Result<(), BlockAllocateError> {
+        let (_, mut allocator) = new_bptree_allocator(1024).unwrap();
+
+        // Single free blocks all the way to the tail, which a search for two skips.
+        let (start, len) = allocator.alloc_extent(512, 512)?;
+        for idx in (start..start + len).step_by(2) {
+            allocator.free(idx)?;
+        }
+        assert_eq!(allocator.alloc_extent(2, 2)?, (start + len, 2));
+        assert_eq!(allocator.alloc_extent(2, 2)?, (start + len + 2, 2));
+
+        // A block freed between two of them makes an extent the next search must not skip.
+        allocator.free(start + 101)?;
+        assert_eq!(allocator.alloc_extent(2, 2)?, (start + 100, 2));
+        assert_eq!(allocator.alloc_extent(2, 2)?, (start + len + 4, 2));
+        // Searches for a single block still start at the front; the tree may have taken the
+        // very first one for a node of its own.
+        assert!(allocator.alloc()? < start + 100);
+        Ok(())
+    }
+
+    #[test]
+    fn free_count_is_kept() -> Result<(), BlockAllocateError> {
+        let (iocontext, mut allocator) = new_bptree_allocator(1024).unwrap();
+        let walked = |allocator: &TestBPTreeAllocator| {
+            allocator
+                .bptree
+                .range(..)
+                .map(|entry| entry.unwrap().1.get())
+                .sum::<u64>()
+        };
+        assert_eq!(allocator.free_blocks_count(), 1022);
+
+        // Enough scattered frees that the tree splits and merges its own nodes on the way.
+        let blocks = (0..900)
+            .map(|_| allocator.alloc())
+            .collect::<Result<Vec<_>, _>>()?;
+        for &idx in blocks.iter().step_by(2) {
+            allocator.free(idx)?;
+        }
+        assert_eq!(allocator.free_blocks_count(), walked(&allocator));
+        allocator.alloc_extent(3, 8)?;
+        allocator.alloc_near(500)?;
+        allocator.alloc_near(3)?;
+        for &idx in blocks.iter().skip(1).step_by(2) {
+            allocator.free(idx)?;
+        }
+        let free = allocator.free_blocks_count();
+        assert_eq!(free, walked(&allocator));
+
+        let reopened = BPTreeAllocator::open(iocontext, 0).unwrap();
+        assert_eq!(reopened.free_blocks_count(), free);
+        Ok(())
+    }
+
+    #[test]
+    fn alloc_near_goal() -> Result<(), BlockAllocateError> {
+        let (_, mut allocator) = new_bptree_allocator(1024).unwrap();
+
+        assert_eq!(allocator.alloc_near(500)?, 500);
+        assert_eq!(allocator.alloc_near(500)?, 501);
+        assert_eq!(allocator.alloc_near(499)?, 499);
+        assert_eq!(allocator.alloc()?, 2);
+
+        let (start, len) = allocator.alloc_extent(1, 1024)?;
+        assert_eq!((start, len), (3, 496));
+        assert_eq!(allocator.alloc_near(1023)?, 1023);
+        assert_eq!(allocator.alloc_near(1023)?, 502);
+        Ok(())
+    }
+
+    #[test]
+    fn double_free() -> Result<(), BlockAllocateError> {
+        let (_, mut allocator) = new_bptree_allocator(1024).unwrap();
+        let idx = allocator.alloc()?;
+        allocator.free(idx)?;
+        assert!(matches!(
+            allocator.free(idx),
+            Err(BlockAllocateError::DoubleFree(_))
+        ));
+        assert!(matches!(
+            allocator.free(1024),
+            Err(BlockAllocateError::IdxOutOfRange)
+        ));
+        Ok(())
     }
 }
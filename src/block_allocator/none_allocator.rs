@@ -9,4 +9,7 @@ impl BlockAllocator for NoneAllocator {
     fn free(&mut self, _: u64) -> Result<(), super::BlockAllocateError> {
         panic!()
     }
+    fn alloc_extent(&mut self, _: u64, _: u64) -> Result<(u64, u64), super::BlockAllocateError> {
+        panic!()
+    }
 }
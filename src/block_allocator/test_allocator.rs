@@ -1,4 +1,36 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use crate::block_allocator::BlockAllocator;
+use crate::block_allocator::bptree_allocator::BPTreeAllocator;
+use crate::block_allocator::none_allocator::NoneAllocator;
+use crate::block_device::mem_disk::MemDisk;
+use crate::io_context::IOContext;
+use crate::utils::bp_tree::BPTreeError;
+use crate::utils::cache::lru::LRU;
+
+pub type TestIOContext = Rc<RefCell<IOContext<MemDisk, LRU<u64, Rc<RefCell<Vec<u8>>>>>>>;
+pub type TestBPTreeAllocator =
+    BPTreeAllocator<MemDisk, LRU<u64, Rc<RefCell<Vec<u8>>>>, NoneAllocator>;
+
+/// An in-memory disk of `blocks` blocks with a fresh free-space tree on it, whose descriptor
+/// is block 0.
+pub fn new_bptree_allocator(
+    blocks: usize,
+) -> Result<(TestIOContext, TestBPTreeAllocator), BPTreeError> {
+    let disk = Rc::new(RefCell::new(MemDisk::new(blocks * 4096)));
+    let iocontext = Rc::new(RefCell::new(IOContext::new(1024, disk)));
+    let allocator = BPTreeAllocator::create(iocontext.clone(), 0)?;
+    Ok((iocontext, allocator))
+}
+
+/// Like `new_bptree_allocator`, with the allocator shared the way trees take it.
+pub fn setup(
+    blocks: usize,
+) -> Result<(TestIOContext, Rc<RefCell<TestBPTreeAllocator>>), BPTreeError> {
+    let (iocontext, allocator) = new_bptree_allocator(blocks)?;
+    Ok((iocontext, Rc::new(RefCell::new(allocator))))
+}
 
 pub struct TestAllocator {
     cur: u64,
@@ -20,4 +52,20 @@ impl BlockAllocator for TestAllocator {
     fn free(&mut self, _idx: u64) -> Result<(), super::BlockAllocateError> {
         Ok(())
     }
+
+    fn alloc_extent(
+        &mut self,
+        _min_len: u64,
+        max_len: u64,
+    ) -> Result<(u64, u64), super::BlockAllocateError> {
+        let rst = Ok((self.cur, max_len));
+        self.cur += max_len;
+        rst
+    }
+}
+
+impl Default for TestAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
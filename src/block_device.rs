@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::utils::cache::CacheError;
+
 pub mod file_disk;
 pub mod mem_disk;
 
@@ -8,6 +10,55 @@ pub trait BlockDevice {
     fn write(&mut self, sector_idx: u64, data: &[u8]) -> Result<(), BlockDeviceError>;
     fn get_capacity(&self) -> u64;
     fn get_block_size(&self) -> u64;
+    /// Returns once everything written so far is on stable storage, so later writes can rely
+    /// on it having landed first.
+    fn sync(&mut self) -> Result<(), BlockDeviceError>;
+
+    /// Reads the consecutive blocks starting at `start` that `buffer` has room for. Its length
+    /// has to be a non-zero multiple of the block size.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let block_size = self.get_block_size();
+        blocks_in(buffer.len(), block_size)?;
+        for (i, block) in buffer.chunks_mut(block_size as usize).enumerate() {
+            self.read(start + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` over the consecutive blocks starting at `start`. Its length has to be a
+    /// non-zero multiple of the block size.
+    fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), BlockDeviceError> {
+        let block_size = self.get_block_size();
+        blocks_in(data.len(), block_size)?;
+        for (i, block) in data.chunks(block_size as usize).enumerate() {
+            self.write(start + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Scatter read: fills each buffer as `read_blocks` would from its starting block.
+    fn read_blocks_vectored(&self, bufs: &mut [(u64, &mut [u8])]) -> Result<(), BlockDeviceError> {
+        for (start, buffer) in bufs {
+            self.read_blocks(*start, buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Gather write: writes each buffer as `write_blocks` would at its starting block.
+    fn write_blocks_vectored(&mut self, bufs: &[(u64, &[u8])]) -> Result<(), BlockDeviceError> {
+        for (start, data) in bufs {
+            self.write_blocks(*start, data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of blocks a buffer of `len` bytes covers, if that is a whole, non-zero number.
+pub(crate) fn blocks_in(len: usize, block_size: u64) -> Result<u64, BlockDeviceError> {
+    if len == 0 || !(len as u64).is_multiple_of(block_size) {
+        return Err(BlockDeviceError::MismatchedBufferSize { size: len as u64 });
+    }
+    Ok(len as u64 / block_size)
 }
 
 #[derive(Error, Debug)]
@@ -18,4 +69,6 @@ pub enum BlockDeviceError {
     MismatchedBufferSize { size: u64 },
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
+    #[error("Cache Error: {0}")]
+    CacheError(#[from] CacheError),
 }
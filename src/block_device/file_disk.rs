@@ -1,10 +1,10 @@
-use crate::block_device::BlockDeviceError;
+use crate::block_device::{BlockDeviceError, blocks_in};
 
 use super::BlockDevice;
 use std::{
     fs::{self, File, OpenOptions},
     io,
-    os::unix::fs::{FileExt, OpenOptionsExt},
+    os::{fd::AsRawFd, unix::fs::FileExt},
 };
 
 #[derive(Debug)]
@@ -39,24 +39,143 @@ impl FileDisk {
         fs::remove_file(&self.path)?;
         Ok(())
     }
+
+    /// Checks that the blocks starting at `start` that `len` bytes cover are all on the disk,
+    /// and returns the byte offset of the first one.
+    fn check_range(&self, start: u64, len: usize) -> Result<u64, BlockDeviceError> {
+        let out_of_range = |idx| BlockDeviceError::IdxOutOfRange {
+            idx,
+            max: self.block_cnt,
+        };
+        let last_idx = start
+            .checked_add(blocks_in(len, BLOCK_SIZE)? - 1)
+            .ok_or(out_of_range(start))?;
+        if last_idx >= self.block_cnt {
+            return Err(out_of_range(last_idx));
+        }
+        start.checked_mul(BLOCK_SIZE).ok_or(out_of_range(start))
+    }
+
+    /// Moves all of `iov` with `preadv`, or `pwritev` if `write`, starting at byte `offset`.
+    /// Short transfers are resumed where they stopped, and at most `UIO_MAXIOV` buffers go in
+    /// one call.
+    fn transfer_vectored(
+        &self,
+        mut iov: Vec<libc::iovec>,
+        mut offset: u64,
+        write: bool,
+    ) -> Result<(), BlockDeviceError> {
+        let fd = self.file.as_raw_fd();
+        let mut first = 0;
+        while first < iov.len() {
+            let cnt = (iov.len() - first).min(libc::UIO_MAXIOV as usize) as libc::c_int;
+            let ptr = iov[first..].as_ptr();
+            // SAFETY: every iovec points into a buffer the caller borrows for the whole call,
+            // mutably when reading.
+            let done = unsafe {
+                if write {
+                    libc::pwritev(fd, ptr, cnt, offset as libc::off_t)
+                } else {
+                    libc::preadv(fd, ptr, cnt, offset as libc::off_t)
+                }
+            };
+            if done < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if done == 0 {
+                return Err(io::Error::from(if write {
+                    io::ErrorKind::WriteZero
+                } else {
+                    io::ErrorKind::UnexpectedEof
+                })
+                .into());
+            }
+            offset += done as u64;
+            let mut done = done as usize;
+            while first < iov.len() && done >= iov[first].iov_len {
+                done -= iov[first].iov_len;
+                first += 1;
+            }
+            if done > 0 {
+                // SAFETY: `done` is less than this buffer's remaining length.
+                iov[first].iov_base = unsafe { iov[first].iov_base.add(done) };
+                iov[first].iov_len -= done;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl BlockDevice for FileDisk {
     fn read(&self, block_idx: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
-        if block_idx >= self.block_cnt {
-            return Err(BlockDeviceError::IdxOutOfRange {
-                idx: block_idx,
-                max: self.block_cnt,
+        if buffer.len() != BLOCK_SIZE as usize {
+            return Err(BlockDeviceError::MismatchedBufferSize {
+                size: buffer.len() as u64,
             });
         }
-        let offset = block_idx * self.get_block_size();
-        self.file.read_at(buffer, offset)?;
-        Ok(())
+        self.read_blocks(block_idx, buffer)
     }
 
     fn write(&mut self, sector_idx: u64, data: &[u8]) -> Result<(), BlockDeviceError> {
-        let offset = sector_idx * self.get_block_size();
-        self.file.write_at(data, offset)?;
+        if data.len() != BLOCK_SIZE as usize {
+            return Err(BlockDeviceError::MismatchedBufferSize {
+                size: data.len() as u64,
+            });
+        }
+        self.write_blocks(sector_idx, data)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let offset = self.check_range(start, buffer.len())?;
+        self.file.read_exact_at(buffer, offset)?;
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), BlockDeviceError> {
+        let offset = self.check_range(start, data.len())?;
+        self.file.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    /// Buffers that continue where the previous one ended go to the file in one `preadv`.
+    fn read_blocks_vectored(&self, bufs: &mut [(u64, &mut [u8])]) -> Result<(), BlockDeviceError> {
+        for (start, buffer) in bufs.iter() {
+            self.check_range(*start, buffer.len())?;
+        }
+        for run in
+            bufs.chunk_by_mut(|(a, a_buf), (b, _)| *a + a_buf.len() as u64 / BLOCK_SIZE == *b)
+        {
+            let iov = run
+                .iter_mut()
+                .map(|(_, buffer)| libc::iovec {
+                    iov_base: buffer.as_mut_ptr().cast(),
+                    iov_len: buffer.len(),
+                })
+                .collect();
+            self.transfer_vectored(iov, run[0].0 * BLOCK_SIZE, false)?;
+        }
+        Ok(())
+    }
+
+    /// Buffers that continue where the previous one ended go to the file in one `pwritev`.
+    fn write_blocks_vectored(&mut self, bufs: &[(u64, &[u8])]) -> Result<(), BlockDeviceError> {
+        for (start, data) in bufs {
+            self.check_range(*start, data.len())?;
+        }
+        for run in bufs.chunk_by(|(a, a_data), (b, _)| a + a_data.len() as u64 / BLOCK_SIZE == *b) {
+            let iov = run
+                .iter()
+                .map(|(_, data)| libc::iovec {
+                    iov_base: data.as_ptr().cast_mut().cast(),
+                    iov_len: data.len(),
+                })
+                .collect();
+            self.transfer_vectored(iov, run[0].0 * BLOCK_SIZE, true)?;
+        }
         Ok(())
     }
 
@@ -67,4 +186,64 @@ impl BlockDevice for FileDisk {
     fn get_block_size(&self) -> u64 {
         BLOCK_SIZE
     }
+
+    /// The file's length is fixed when it is created, so only the data needs syncing.
+    fn sync(&mut self) -> Result<(), BlockDeviceError> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vectored_round_trip() -> Result<(), BlockDeviceError> {
+        let path = std::env::temp_dir().join(format!("bpfs-file-disk-{}", std::process::id()));
+        let mut disk = FileDisk::new(path.to_str().unwrap(), 16 * BLOCK_SIZE);
+
+        let blocks: Vec<Vec<u8>> = (1..=4u8).map(|i| vec![i; BLOCK_SIZE as usize]).collect();
+        let two = [blocks[1].as_slice(), &blocks[2]].concat();
+        // Blocks 3 and 4 continue block 2, so they share a `pwritev`; block 9 gets its own.
+        disk.write_blocks_vectored(&[(2, &blocks[0]), (3, &two), (9, &blocks[3])])?;
+
+        let mut first = vec![0; 2 * BLOCK_SIZE as usize];
+        let mut second = vec![0; BLOCK_SIZE as usize];
+        let mut third = vec![0; BLOCK_SIZE as usize];
+        disk.read_blocks_vectored(&mut [(2, &mut first), (4, &mut second), (9, &mut third)])?;
+        assert_eq!(first, [blocks[0].as_slice(), &blocks[1]].concat());
+        assert_eq!(second, blocks[2]);
+        assert_eq!(third, blocks[3]);
+
+        let mut buf = vec![0; 8 * BLOCK_SIZE as usize];
+        disk.read_blocks(2, &mut buf)?;
+        assert_eq!(buf[2 * BLOCK_SIZE as usize], 3);
+        assert_eq!(buf[3 * BLOCK_SIZE as usize], 0);
+        assert!(matches!(
+            disk.write_blocks(15, &two),
+            Err(BlockDeviceError::IdxOutOfRange { idx: 16, max: 16 })
+        ));
+        assert!(matches!(
+            disk.write(16, &blocks[0]),
+            Err(BlockDeviceError::IdxOutOfRange { idx: 16, max: 16 })
+        ));
+        assert!(matches!(
+            disk.read_blocks(u64::MAX, &mut first),
+            Err(BlockDeviceError::IdxOutOfRange { .. })
+        ));
+        assert!(matches!(
+            disk.write_blocks_vectored(&[(u64::MAX / BLOCK_SIZE, &blocks[0])]),
+            Err(BlockDeviceError::IdxOutOfRange { .. })
+        ));
+        assert!(matches!(
+            disk.read(2, &mut first),
+            Err(BlockDeviceError::MismatchedBufferSize { .. })
+        ));
+        disk.read(9, &mut second)?;
+        assert_eq!(second, blocks[3]);
+
+        disk.remove()?;
+        Ok(())
+    }
 }
@@ -1,4 +1,6 @@
-use crate::block_device::BlockDeviceError;
+use std::ops::Range;
+
+use crate::block_device::{BlockDeviceError, blocks_in};
 
 use super::BlockDevice;
 
@@ -22,46 +24,43 @@ impl MemDisk {
         }
     }
 
-    fn get_range_from_idx(sector_idx: usize) -> (usize, usize) {
-        let start = sector_idx * BLOCK_SIZE as usize;
-        let end = start + BLOCK_SIZE as usize;
-        (start, end)
+    /// Byte range of the blocks starting at `sector_idx` that `len` bytes cover.
+    fn get_range(&self, sector_idx: u64, len: usize) -> Result<Range<usize>, BlockDeviceError> {
+        let out_of_range = |idx| BlockDeviceError::IdxOutOfRange {
+            idx,
+            max: self.sector_cnt as u64,
+        };
+        let last_idx = sector_idx
+            .checked_add(blocks_in(len, BLOCK_SIZE)? - 1)
+            .ok_or(out_of_range(sector_idx))?;
+        if last_idx >= self.sector_cnt as u64 {
+            return Err(out_of_range(last_idx));
+        }
+        let start = usize::try_from(sector_idx)
+            .ok()
+            .and_then(|idx| idx.checked_mul(BLOCK_SIZE as usize))
+            .ok_or(out_of_range(sector_idx))?;
+        Ok(start..start + len)
     }
 }
 
 impl BlockDevice for MemDisk {
     fn read(&self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
-        if sector_idx as usize >= self.sector_cnt {
-            return Err(BlockDeviceError::IdxOutOfRange {
-                idx: sector_idx,
-                max: self.sector_cnt as u64,
-            });
-        }
         if buffer.len() != self.get_block_size() as usize {
             return Err(BlockDeviceError::MismatchedBufferSize {
                 size: buffer.len() as u64,
             });
         }
-        let (start, end) = Self::get_range_from_idx(sector_idx as usize);
-        buffer.clone_from_slice(&self.data[start..end]);
-        Ok(())
+        self.read_blocks(sector_idx, buffer)
     }
 
     fn write(&mut self, sector_idx: u64, data: &[u8]) -> Result<(), BlockDeviceError> {
-        if sector_idx >= self.sector_cnt as u64 {
-            return Err(BlockDeviceError::IdxOutOfRange {
-                idx: sector_idx,
-                max: self.sector_cnt as u64,
-            });
-        }
         if data.len() != BLOCK_SIZE as usize {
             return Err(BlockDeviceError::MismatchedBufferSize {
                 size: data.len() as u64,
             });
         }
-        let (start, end) = Self::get_range_from_idx(sector_idx as usize);
-        self.data[start..end].clone_from_slice(&data);
-        Ok(())
+        self.write_blocks(sector_idx, data)
     }
 
     fn get_capacity(&self) -> u64 {
@@ -71,4 +70,20 @@ impl BlockDevice for MemDisk {
     fn get_block_size(&self) -> u64 {
         BLOCK_SIZE
     }
+
+    fn sync(&mut self) -> Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let range = self.get_range(start, buffer.len())?;
+        buffer.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, data: &[u8]) -> Result<(), BlockDeviceError> {
+        let range = self.get_range(start, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
 }
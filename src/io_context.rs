@@ -2,15 +2,21 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::block_device::{BlockDevice, BlockDeviceError};
-use crate::utils::cache::Cache;
+use crate::utils::cache::{Cache, Pinnable};
 
 pub mod data_block;
 pub use data_block::{MutableBlock, ReadOnlyBlock};
 
-pub struct IOContext<D, C> {
+pub struct IOContext<D, C>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
     cache: C,
     disk: Rc<RefCell<D>>,
     block_size: u64,
+    /// Whether blocks were written to the device since it was last synced.
+    unsynced: bool,
 }
 
 impl<D, C> IOContext<D, C>
@@ -23,6 +29,7 @@ where
             cache: C::new(cache_size),
             disk: disk.clone(),
             block_size: disk.borrow_mut().get_block_size(),
+            unsynced: false,
         }
     }
 
@@ -34,7 +41,7 @@ where
         let mut v = vec![0u8; self.block_size as usize];
         self.disk.borrow_mut().read(block_idx, &mut v)?;
         let v = Rc::new(RefCell::new(v));
-        if let Some(entry) = self.cache.put(block_idx, v.clone(), false) {
+        if let Some(entry) = self.cache.put(block_idx, v.clone(), false)? {
             self.flush_block(entry)?;
         }
 
@@ -49,7 +56,7 @@ where
         let mut v = vec![0u8; self.block_size as usize];
         self.disk.borrow_mut().read(block_idx, &mut v)?;
         let v = Rc::new(RefCell::new(v));
-        if let Some(entry) = self.cache.put(block_idx, v.clone(), true) {
+        if let Some(entry) = self.cache.put(block_idx, v.clone(), true)? {
             self.flush_block(entry)?;
         }
 
@@ -65,26 +72,101 @@ where
     }
 
     fn flush_block(
-        &self,
+        &mut self,
         entry: (u64, Rc<RefCell<Vec<u8>>>, bool),
     ) -> Result<(), BlockDeviceError> {
         if !entry.2 {
             return Ok(());
         }
+        self.unsynced = true;
         self.disk
             .borrow_mut()
             .write(entry.0, &entry.1.borrow_mut())?;
         Ok(())
     }
 
+    /// Writes back every dirty block and empties the cache. The blocks are durable once this
+    /// returns; the device is only synced if something was written to it since the last sync.
+    /// Blocks still handed out stay cached, and keep their dirty flag as with `sync`,
+    /// since a `MutableBlock` may be written to again.
     pub fn flush(&mut self) -> Result<(), BlockDeviceError> {
-        let disk = &mut self.disk;
-        let entries = &mut self.cache.drain();
-        for entry in entries {
-            if !entry.2 {
-                continue;
+        let mut pinned = Vec::new();
+        let mut dirty = Vec::new();
+        for (block_idx, block, is_dirty) in self.cache.drain() {
+            if block.is_pinned() {
+                pinned.push((block_idx, block.clone(), is_dirty));
+            }
+            if is_dirty {
+                dirty.push((block_idx, block));
+            }
+        }
+        self.write_back(dirty)?;
+        for (block_idx, block, is_dirty) in pinned {
+            if let Some(entry) = self.cache.put(block_idx, block, is_dirty)? {
+                self.flush_block(entry)?;
+            }
+        }
+        if self.unsynced {
+            self.sync_disk()?;
+        }
+        Ok(())
+    }
+
+    /// Write-ordering barrier: every block written before this is durable before any block
+    /// written after it reaches the device. The cache stays warm, as with `sync`.
+    pub fn barrier(&mut self) -> Result<(), BlockDeviceError> {
+        self.sync()?;
+        self.sync_disk()
+    }
+
+    fn sync_disk(&mut self) -> Result<(), BlockDeviceError> {
+        self.disk.borrow_mut().sync()?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Writes back every dirty block like `flush`, but keeps them all cached. Blocks still
+    /// handed out as a `MutableBlock` stay dirty, since they may be written to again.
+    pub fn sync(&mut self) -> Result<(), BlockDeviceError> {
+        let dirty: Vec<_> = self
+            .cache
+            .dirty()
+            .map(|(&block_idx, block)| (block_idx, block.clone()))
+            .collect();
+        let written: Vec<_> = dirty.iter().map(|&(block_idx, _)| block_idx).collect();
+        self.write_back(dirty)?;
+        for block_idx in written {
+            if !self
+                .cache
+                .peek(block_idx)
+                .is_some_and(|block| block.is_pinned())
+            {
+                self.cache.mark_clean(&block_idx);
             }
-            disk.borrow_mut().write(entry.0, &entry.1.borrow_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Writes `blocks` in order of block index, with each run of consecutive blocks going to
+    /// the device as a single gather write.
+    fn write_back(
+        &mut self,
+        mut blocks: Vec<(u64, Rc<RefCell<Vec<u8>>>)>,
+    ) -> Result<(), BlockDeviceError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        self.unsynced = true;
+        blocks.sort_unstable_by_key(|&(block_idx, _)| block_idx);
+        let mut disk = self.disk.borrow_mut();
+        for run in blocks.chunk_by(|(a, _), (b, _)| a + 1 == *b) {
+            let data: Vec<_> = run.iter().map(|(_, block)| block.borrow()).collect();
+            let bufs: Vec<_> = run
+                .iter()
+                .zip(&data)
+                .map(|(&(block_idx, _), data)| (block_idx, data.as_slice()))
+                .collect();
+            disk.write_blocks_vectored(&bufs)?;
         }
         Ok(())
     }
@@ -93,3 +175,212 @@ where
         self.cache.clear();
     }
 }
+
+/// Writes back whatever is still dirty. Errors can't be reported from here, so call `flush`
+/// or `sync` first to see them.
+impl<D, C> Drop for IOContext<D, C>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block_device::mem_disk::MemDisk;
+    use crate::utils::cache::CacheError;
+    use crate::utils::cache::lru::LRU;
+
+    #[test]
+    fn pinned_blocks_are_not_evicted() -> Result<(), BlockDeviceError> {
+        let disk = Rc::new(RefCell::new(MemDisk::new(16 * 4096)));
+        let mut ioc = IOContext::<MemDisk, LRU<u64, Rc<RefCell<Vec<u8>>>>>::new(2, disk.clone());
+
+        let first = ioc.get_mut(0)?;
+        first.get()[0] = 1;
+        ioc.get(1)?;
+        // Block 0 is the least recently used, but still held, so block 1 goes instead.
+        ioc.get(2)?;
+        first.get()[1] = 2;
+        let second = ioc.get(3)?;
+        assert!(matches!(
+            ioc.get(4),
+            Err(BlockDeviceError::CacheError(CacheError::AllPinned))
+        ));
+
+        drop((first, second));
+        ioc.get(4)?;
+        ioc.get(5)?;
+        let mut buf = vec![0; 4096];
+        disk.borrow().read(0, &mut buf)?;
+        assert_eq!(buf[..2], [1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_keeps_blocks_cached() -> Result<(), BlockDeviceError> {
+        let disk = Rc::new(RefCell::new(MemDisk::new(16 * 4096)));
+        let mut ioc = IOContext::<MemDisk, LRU<u64, Rc<RefCell<Vec<u8>>>>>::new(4, disk.clone());
+
+        ioc.get_mut(0)?.get()[0] = 1;
+        let held = ioc.get_mut(1)?;
+        held.get()[0] = 2;
+        ioc.sync()?;
+        let mut buf = vec![0; 4096];
+        disk.borrow().read(0, &mut buf)?;
+        assert_eq!(buf[0], 1);
+        disk.borrow().read(1, &mut buf)?;
+        assert_eq!(buf[0], 2);
+        assert!(ioc.cache.peek(0).is_some());
+        assert_eq!(
+            ioc.cache.dirty().map(|(&idx, _)| idx).collect::<Vec<_>>(),
+            [1]
+        );
+
+        // Still dirty because it was held during the sync, so this write is not lost.
+        held.get()[0] = 3;
+        drop(held);
+        drop(ioc);
+        disk.borrow().read(1, &mut buf)?;
+        assert_eq!(buf[0], 3);
+        Ok(())
+    }
+
+    #[test]
+    fn flush_keeps_held_blocks() -> Result<(), BlockDeviceError> {
+        let disk = Rc::new(RefCell::new(MemDisk::new(16 * 4096)));
+        let mut ioc = IOContext::<MemDisk, LRU<u64, Rc<RefCell<Vec<u8>>>>>::new(4, disk.clone());
+
+        ioc.get_mut(0)?.get()[0] = 1;
+        let held = ioc.get_mut(1)?;
+        held.get()[0] = 2;
+        let read_only = ioc.get(2)?;
+        ioc.flush()?;
+        let mut buf = vec![0; 4096];
+        disk.borrow().read(1, &mut buf)?;
+        assert_eq!(buf[0], 2);
+        assert!(ioc.cache.peek(0).is_none());
+        // Held only for reading, so it stays cached but is not written again.
+        assert!(ioc.cache.peek(2).is_some());
+        assert_eq!(
+            ioc.cache.dirty().map(|(&idx, _)| idx).collect::<Vec<_>>(),
+            [1]
+        );
+        drop(read_only);
+
+        // The held block is the one still cached, so a write to it after the flush reaches
+        // the disk and later reads see it.
+        held.get()[0] = 3;
+        drop(held);
+        assert_eq!(ioc.get(1)?.get()[0], 3);
+        ioc.flush()?;
+        disk.borrow().read(1, &mut buf)?;
+        assert_eq!(buf[0], 3);
+        Ok(())
+    }
+
+    /// Records the first block and block count of each write, and how many writes came before
+    /// each sync.
+    struct CountingDisk {
+        disk: MemDisk,
+        writes: Vec<(u64, u64)>,
+        syncs: Vec<usize>,
+    }
+
+    impl BlockDevice for CountingDisk {
+        fn read(&self, sector_idx: u64, buffer: &mut [u8]) -> Result<(), BlockDeviceError> {
+            self.disk.read(sector_idx, buffer)
+        }
+
+        fn write(&mut self, sector_idx: u64, data: &[u8]) -> Result<(), BlockDeviceError> {
+            self.writes.push((sector_idx, 1));
+            self.disk.write(sector_idx, data)
+        }
+
+        fn get_capacity(&self) -> u64 {
+            self.disk.get_capacity()
+        }
+
+        fn get_block_size(&self) -> u64 {
+            self.disk.get_block_size()
+        }
+
+        fn sync(&mut self) -> Result<(), BlockDeviceError> {
+            self.syncs.push(self.writes.len());
+            self.disk.sync()
+        }
+
+        fn write_blocks_vectored(&mut self, bufs: &[(u64, &[u8])]) -> Result<(), BlockDeviceError> {
+            let len: usize = bufs.iter().map(|(_, data)| data.len()).sum();
+            self.writes
+                .push((bufs[0].0, len as u64 / self.get_block_size()));
+            self.disk.write_blocks_vectored(bufs)
+        }
+    }
+
+    #[test]
+    fn writeback_is_sorted_and_coalesced() -> Result<(), BlockDeviceError> {
+        let disk = Rc::new(RefCell::new(CountingDisk {
+            disk: MemDisk::new(16 * 4096),
+            writes: Vec::new(),
+            syncs: Vec::new(),
+        }));
+        let mut ioc = IOContext::<_, LRU<u64, Rc<RefCell<Vec<u8>>>>>::new(8, disk.clone());
+
+        for block_idx in [9, 3, 1, 2, 7, 8] {
+            ioc.get_mut(block_idx)?.get()[0] = block_idx as u8;
+        }
+        ioc.get(4)?;
+        ioc.sync()?;
+        assert_eq!(disk.borrow().writes, [(1, 3), (7, 3)]);
+
+        disk.borrow_mut().writes.clear();
+        ioc.get_mut(5)?;
+        ioc.get_mut(4)?;
+        ioc.get_mut(12)?;
+        ioc.flush()?;
+        assert_eq!(disk.borrow().writes, [(4, 2), (12, 1)]);
+
+        let mut buf = vec![0; 4096];
+        for block_idx in [1, 2, 3, 7, 8, 9] {
+            disk.borrow().read(block_idx, &mut buf)?;
+            assert_eq!(buf[0], block_idx as u8);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn barrier_syncs_after_writeback() -> Result<(), BlockDeviceError> {
+        let disk = Rc::new(RefCell::new(CountingDisk {
+            disk: MemDisk::new(16 * 4096),
+            writes: Vec::new(),
+            syncs: Vec::new(),
+        }));
+        let mut ioc = IOContext::<_, LRU<u64, Rc<RefCell<Vec<u8>>>>>::new(8, disk.clone());
+
+        ioc.get_mut(2)?;
+        ioc.get_mut(1)?;
+        ioc.barrier()?;
+        assert_eq!(disk.borrow().writes, [(1, 2)]);
+        assert_eq!(disk.borrow().syncs, [1]);
+        assert!(ioc.cache.peek(1).is_some());
+
+        // Nothing is dirty, but the barrier still has to reach the device.
+        ioc.barrier()?;
+        ioc.get_mut(5)?;
+        ioc.flush()?;
+        assert_eq!(disk.borrow().writes, [(1, 2), (5, 1)]);
+        assert_eq!(disk.borrow().syncs, [1, 1, 2]);
+
+        // Nothing was written since, so neither flush needs the device.
+        ioc.get(6)?;
+        ioc.flush()?;
+        ioc.flush()?;
+        assert_eq!(disk.borrow().syncs, [1, 1, 2]);
+        Ok(())
+    }
+}
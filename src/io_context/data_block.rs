@@ -3,6 +3,7 @@ use std::{
     rc::Rc,
 };
 
+/// Cached block handed out for reading. The cache will not evict it while this is alive.
 pub struct ReadOnlyBlock {
     data: Rc<RefCell<Vec<u8>>>,
 }
@@ -21,6 +22,8 @@ impl ReadOnlyBlock {
     }
 }
 
+/// Cached block handed out for writing, already marked dirty. The cache will not evict it while
+/// this is alive, so nothing written through it is lost.
 pub struct MutableBlock {
     data: Rc<RefCell<Vec<u8>>>,
 }
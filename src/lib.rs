@@ -1,13 +1,19 @@
 use std::{cell::RefCell, rc::Rc};
 use thiserror::Error;
-use zerocopy::{FromBytes, little_endian::U64};
+use zerocopy::{FromBytes, IntoBytes, little_endian::U64};
 
 use crate::{
-    block_allocator::{bptree_allocator::BPTreeAllocator, none_allocator::NoneAllocator},
+    block_allocator::{
+        BlockAllocateError, BlockAllocator, bptree_allocator::BPTreeAllocator,
+        none_allocator::NoneAllocator,
+    },
     block_device::{BlockDevice, BlockDeviceError},
     io_context::IOContext,
     super_block::{MAGIC_NUMBER, SuperBlock},
-    utils::{bp_tree::BPTreeError, cache::Cache},
+    utils::{
+        bp_tree::{BPTree, BPTreeError},
+        cache::Cache,
+    },
 };
 
 pub mod block_allocator;
@@ -22,61 +28,189 @@ pub enum FsError {
     BlockDeviceError(#[from] BlockDeviceError),
     #[error("Failed to read super block from disk")]
     ReadSuperBlockError,
+    #[error("Failed to write super block to disk")]
+    WriteSuperBlockError,
     #[error("B+ Tree Error: {0}")]
     BPTreeError(#[from] BPTreeError),
+    #[error("Block allocate error: {0}")]
+    BlockAllocateError(#[from] BlockAllocateError),
+    #[error("Disk is too small to hold a file system: {blocks_count} blocks")]
+    DiskTooSmall { blocks_count: u64 },
 }
 
-pub struct FS<D, C, A> {
+pub struct FS<D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
     io_context: Rc<RefCell<IOContext<D, C>>>,
-    block_manager: A,
+    block_manager: Rc<RefCell<A>>,
+    inode_manager: BPTree<D, C, A>,
     super_block: SuperBlock,
 }
 
 const CACHE_SIZE: u64 = 1024;
 
+/// Block holding the `SuperBlock`.
+pub const SUPER_BLOCK_IDX: u64 = 0;
+/// Descriptor of the free-space B+ tree, right after the super block. Its root follows it.
+pub const FREE_BLOCKS_MANAGER_IDX: u64 = 1;
+
 impl<D, C> FS<D, C, BPTreeAllocator<D, C, NoneAllocator>>
 where
     D: BlockDevice,
     C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
 {
+    /// Mounts the file system on `disk`, formatting it first if it holds no valid super block.
     pub fn try_new(disk: Rc<RefCell<D>>) -> Result<Self, FsError> {
         let io_context = Rc::new(RefCell::new(IOContext::<D, C>::new(CACHE_SIZE, disk)));
-        let block_manager = BPTreeAllocator::try_new(io_context.clone(), 114514)?;
 
-        let sb_block = io_context.borrow_mut().get(0)?;
-        let sb_block = sb_block.get();
-        let super_block =
-            SuperBlock::read_from_bytes(&sb_block).map_err(|_| FsError::ReadSuperBlockError)?;
+        let super_block = {
+            let sb_block = io_context.borrow_mut().get(SUPER_BLOCK_IDX)?;
+            let sb_block = sb_block.get();
+            SuperBlock::read_from_prefix(&sb_block)
+                .map_err(|_| FsError::ReadSuperBlockError)?
+                .0
+        };
 
-        let mut fs = Self {
+        if super_block.magic.get() != MAGIC_NUMBER {
+            return Self::formatting(io_context);
+        }
+
+        let block_manager = Rc::new(RefCell::new(BPTreeAllocator::open(
+            io_context.clone(),
+            super_block.free_blocks_manager_block.get(),
+        )?));
+        let inode_manager = BPTree::open(
+            super_block.free_inodes_manager_block.get(),
+            io_context.clone(),
+            block_manager.clone(),
+        )?;
+
+        Ok(Self {
             io_context,
             block_manager,
+            inode_manager,
             super_block,
+        })
+    }
+
+    /// Lays out an empty file system:
+    ///
+    /// - block 0: `SuperBlock`
+    /// - block 1: descriptor of the free-space B+ tree
+    /// - block 2: root of the free-space B+ tree, which owns every block after it
+    /// - first allocated block: descriptor of the inode B+ tree
+    pub fn formatting(io_context: Rc<RefCell<IOContext<D, C>>>) -> Result<Self, FsError> {
+        let (block_size, blocks_count) = {
+            let mut ioc = io_context.borrow_mut();
+            ioc.clear_cache();
+            let block_size = ioc.get_disk_block_size();
+            (block_size, ioc.get_disk_capacity() / block_size)
         };
+        if blocks_count <= FREE_BLOCKS_MANAGER_IDX + 3 {
+            return Err(FsError::DiskTooSmall { blocks_count });
+        }
+
+        let block_manager = Rc::new(RefCell::new(BPTreeAllocator::create(
+            io_context.clone(),
+            FREE_BLOCKS_MANAGER_IDX,
+        )?));
+        let inode_desc = block_manager.borrow_mut().alloc()?;
+        let inode_manager = BPTree::create(inode_desc, io_context.clone(), block_manager.clone())?;
+
+        let super_block = SuperBlock {
+            magic: MAGIC_NUMBER.into(),
+
+            block_size: block_size.into(),
+            blocks_count: blocks_count.into(),
+            free_blocks_count: U64::ZERO,
 
-        if fs.super_block.magic.get() != MAGIC_NUMBER {
-            fs.formatting()?;
+            free_blocks_manager_block: FREE_BLOCKS_MANAGER_IDX.into(),
+            free_inodes_manager_block: inode_desc.into(),
         };
 
+        let mut fs = Self {
+            io_context,
+            block_manager,
+            inode_manager,
+            super_block,
+        };
+        fs.flush()?;
         Ok(fs)
     }
 
-    pub fn formatting(&mut self) -> Result<(), FsError> {
+    /// Writes the super block back, with the free block count kept by the free-space tree,
+    /// and flushes every dirty block to disk.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        self.super_block.free_blocks_count = self.block_manager.borrow().free_blocks_count().into();
         let mut ioc = self.io_context.borrow_mut();
-        ioc.clear_cache();
+        {
+            let sb_block = ioc.get_mut(SUPER_BLOCK_IDX)?;
+            let mut sb_block = sb_block.get();
+            self.super_block
+                .write_to_prefix(&mut sb_block)
+                .map_err(|_| FsError::WriteSuperBlockError)?;
+        }
+        ioc.flush()?;
+        Ok(())
+    }
 
-        let blocks_count = ioc.get_disk_capacity() / ioc.get_disk_block_size();
+    pub fn super_block(&self) -> &SuperBlock {
+        &self.super_block
+    }
 
-        self.super_block = SuperBlock {
-            magic: MAGIC_NUMBER.into(),
+    pub fn block_manager(&self) -> Rc<RefCell<BPTreeAllocator<D, C, NoneAllocator>>> {
+        self.block_manager.clone()
+    }
 
-            block_size: ioc.get_disk_block_size().into(),
-            blocks_count: blocks_count.into(),
-            free_blocks_count: U64::new(blocks_count) - 1,
+    pub fn inode_manager(&mut self) -> &mut BPTree<D, C, BPTreeAllocator<D, C, NoneAllocator>> {
+        &mut self.inode_manager
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{block_device::mem_disk::MemDisk, utils::cache::lru::LRU};
+
+    use super::*;
 
-            free_blocks_manager_block: todo!(),
-            free_inodes_manager_block: todo!(),
+    type TestFS = FS<
+        MemDisk,
+        LRU<u64, Rc<RefCell<Vec<u8>>>>,
+        BPTreeAllocator<MemDisk, LRU<u64, Rc<RefCell<Vec<u8>>>>, NoneAllocator>,
+    >;
+
+    #[test]
+    fn format_and_mount() -> Result<(), FsError> {
+        let disk = Rc::new(RefCell::new(MemDisk::new(4 * 1024 * 1024)));
+        let blocks_count = 1024;
+
+        {
+            let mut fs = TestFS::try_new(disk.clone())?;
+            let sb = fs.super_block();
+            assert_eq!(sb.magic.get(), MAGIC_NUMBER);
+            assert_eq!(sb.blocks_count.get(), blocks_count);
+            assert_eq!(sb.free_blocks_manager_block.get(), FREE_BLOCKS_MANAGER_IDX);
+            // Super block, free-space tree descriptor and root, inode tree descriptor.
+            assert_eq!(sb.free_blocks_count.get(), blocks_count - 4);
+            fs.inode_manager().insert(1.into(), 42.into())?;
+            fs.flush()?;
+            assert_eq!(fs.super_block().free_blocks_count.get(), blocks_count - 5);
+        }
+
+        let allocated = {
+            let mut fs = TestFS::try_new(disk.clone())?;
+            assert_eq!(fs.super_block().blocks_count.get(), blocks_count);
+            assert_eq!(fs.inode_manager().get(1.into())?, Some(42.into()));
+            let allocated = fs.block_manager().borrow_mut().alloc()?;
+            fs.flush()?;
+            allocated
         };
-        todo!()
+
+        let fs = TestFS::try_new(disk)?;
+        assert_eq!(fs.super_block().free_blocks_count.get(), blocks_count - 6);
+        assert_ne!(fs.block_manager().borrow_mut().alloc()?, allocated);
+        Ok(())
     }
 }
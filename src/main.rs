@@ -1,8 +1,10 @@
 use std::{cell::RefCell, rc::Rc};
 
+use zerocopy::little_endian::U64;
+
 use bpfs::{
     block_allocator::{bptree_allocator::BPTreeAllocator, none_allocator::NoneAllocator},
-    block_device::{file_disk::FileDisk, mem_disk::MemDisk},
+    block_device::file_disk::FileDisk,
     io_context::IOContext,
     utils::{
         bp_tree::{BPTree, BPTreeError},
@@ -30,7 +32,7 @@ fn main() -> Result<(), BPTreeError> {
         FileDisk,
         LRU<u64, Rc<RefCell<Vec<u8>>>>,
         NoneAllocator,
-    >::try_new(iocontext.clone(), 0)?));
+    >::create(iocontext.clone(), 0)?));
 
     let mut bptree = BPTree::new(iocontext.clone(), allocator.clone());
     let m = bptree.get_m();
@@ -50,8 +52,8 @@ fn main() -> Result<(), BPTreeError> {
     // }
 
     for i in 0..test_size * m {
-        let key = 3;
-        let val = pseudo_random_mapper(i);
+        let key = U64::new(3);
+        let val = U64::new(pseudo_random_mapper(i));
         bptree.insert(key, val)?;
         assert_eq!(bptree.get(key)?, Some(val));
     }
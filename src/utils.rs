@@ -1,2 +1,3 @@
 pub mod cache;
 pub mod bp_tree;
+pub mod var_bp_tree;
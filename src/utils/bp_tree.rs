@@ -1,30 +1,90 @@
 use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
 use std::rc::Rc;
 
+mod batch;
 mod bp_tree_node;
+mod bulk_load;
+mod order;
+mod range;
+mod stats;
+mod verify;
+
+pub use batch::BatchOp;
+pub use range::Range;
+pub use stats::{LevelStats, TreeStats};
+pub use verify::Violation;
 
 use thiserror::Error;
-use zerocopy::FromBytes;
 use zerocopy::little_endian::U64;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
 
 use crate::block_allocator::none_allocator::NoneAllocator;
-use crate::block_allocator::{BlockAllocateError, BlockAllocator};
+use crate::block_allocator::{BlockAllocateError, BlockAllocator, ExtentPolicy};
 use crate::block_device::BlockDeviceError;
 use crate::io_context::IOContext;
-use crate::utils::bp_tree::bp_tree_node::{NodeHeader, NodeParseError, NodeView, NodeViewMut};
+use crate::utils::bp_tree::bp_tree_node::{
+    Child, Node, NodeCheck, NodeHeader, NodeParseError, NodeView, NodeViewMut, SnapshotRecord,
+    TREE_COW, TREE_MAGIC, TreeDescriptor,
+};
 use crate::{block_device::BlockDevice, utils::cache::Cache};
 
-pub struct BPTree<D, C, A>
+/// Fixed-size type stored in place in a node, such as `U64` or a `#[repr(C)]` struct of them.
+///
+/// Keys additionally need `Ord`; the order of the tree is the order of the type.
+pub trait NodeItem: FromBytes + IntoBytes + Unaligned + Immutable + KnownLayout + Copy {}
+
+impl<T> NodeItem for T where T: FromBytes + IntoBytes + Unaligned + Immutable + KnownLayout + Copy {}
+
+/// Carves a block for one of the free-space tree's own nodes out of its extents.
+type TakeBlock<T> = fn(&mut T, u64) -> Result<u64, BPTreeError>;
+
+pub struct BPTree<D, C, A, K = U64, V = U64>
 where
     D: BlockDevice,
     C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
 {
     io_context: Rc<RefCell<IOContext<D, C>>>,
+    /// Block holding the on-disk `TreeDescriptor`, for trees that outlive an unmount.
+    desc_block: Option<u64>,
     root_block: Option<u64>,
     first_leaf: u64,
-    is_block_manager: bool,
+    height: u64,
+    num_keys: u64,
     allocator: Option<Rc<RefCell<A>>>,
     m: u64,
+    /// Free-space tree only: where its nodes come from, since it cannot call an allocator.
+    take_block: Option<TakeBlock<Self>>,
+    /// Free-space tree only: blocks released while the tree was being restructured.
+    pending_free: Vec<u64>,
+    /// Free-space tree only: extents shrunk to zero length by `take_block_for_node`.
+    emptied: Vec<u64>,
+    /// Free-space tree only: `(len, start)` such that no extent before `start` holds `len`
+    /// blocks or more, so searches for that many start there.
+    fit_hint: Option<(u64, u64)>,
+    /// Free-space tree only: blocks in all its extents.
+    free_blocks: u64,
+    /// Nodes written before the last commit are copied before they are changed, and sibling
+    /// links are not kept.
+    cow: bool,
+    /// Nodes replaced or given up since the last commit. The descriptor on disk may still point
+    /// at them, so they are released only once the new one is written, and for copy-on-write
+    /// trees durable.
+    retired: Vec<u64>,
+    /// Blocks `apply_batch` set aside before changing anything, handed out by `alloc` first.
+    reserved: Vec<u64>,
+    /// Copy-on-write trees only: snapshots that still hold a reference to their root, oldest
+    /// first.
+    snapshots: Vec<SnapshotRecord>,
+    /// Set on snapshots, which must not change.
+    read_only: bool,
+    /// Stamped on every node, so nodes of another tree are told apart: the descriptor block, or
+    /// `u64::MAX` for trees without one.
+    tree_id: u64,
+    /// Bumped on every commit; nodes record the one they were written in.
+    generation: u64,
+    _items: PhantomData<(K, V)>,
 }
 
 #[derive(Error, Debug)]
@@ -41,118 +101,493 @@ pub enum BPTreeError {
     EmptyTree,
     #[error("Calling the extent method on a regular B+ tree")]
     IllegalUse,
+    #[error("Extent ({start}, {len}) overlaps a free extent")]
+    ExtentOverlap { start: u64, len: u64 },
+    #[error("Fill factor must be in (0, 1], got {0}")]
+    InvalidFillFactor(f64),
+    #[error("Bulk load needs an empty tree")]
+    NotEmpty,
+    #[error("Snapshots are read-only")]
+    ReadOnly,
+    #[error("Bulk load input is not sorted by strictly increasing key at entry {index}")]
+    UnsortedInput { index: u64 },
+    #[error("Node in block {block} does not match the tree structure")]
+    Corrupted { block: u64 },
+    #[error("Block {block} does not hold a B+ tree descriptor")]
+    BadDescriptor { block: u64 },
+    #[error("Fanout on disk is {found}, but this block size needs {expected}")]
+    FanoutMismatch { found: u64, expected: u64 },
+    #[error("Key of {len} bytes is longer than the {max} bytes a node can hold")]
+    KeyTooLong { len: usize, max: usize },
+    #[error("Descriptor block has no room for another snapshot")]
+    TooManySnapshots,
 }
 
-impl<D, C, A> BPTree<D, C, A>
+impl<D, C, A, K, V> BPTree<D, C, A, K, V>
 where
     D: BlockDevice,
     C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
     A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
 {
+    /// Creates an empty tree that lives only as long as this handle.
     pub fn new(ioc: Rc<RefCell<IOContext<D, C>>>, allocator: Rc<RefCell<A>>) -> Self {
+        Self::with_parts(ioc, Some(allocator), None)
+    }
+
+    /// Creates an empty tree whose descriptor lives in `desc_block`.
+    pub fn create(
+        desc_block: u64,
+        ioc: Rc<RefCell<IOContext<D, C>>>,
+        allocator: Rc<RefCell<A>>,
+    ) -> Result<Self, BPTreeError> {
+        let mut tree = Self::with_parts(ioc, Some(allocator), None);
+        tree.desc_block = Some(desc_block);
+        tree.tree_id = desc_block;
+        tree.write_descriptor()?;
+        Ok(tree)
+    }
+
+    /// Reopens the tree whose descriptor was written to `desc_block`.
+    pub fn open(
+        desc_block: u64,
+        ioc: Rc<RefCell<IOContext<D, C>>>,
+        allocator: Rc<RefCell<A>>,
+    ) -> Result<Self, BPTreeError> {
+        let mut tree = Self::with_parts(ioc, Some(allocator), None);
+        tree.read_descriptor(desc_block)?;
+        Ok(tree)
+    }
+
+    /// Switches the tree to copy-on-write: from now on changes write the nodes they touch and
+    /// the path above them to fresh blocks, and only take effect on disk once `commit` names
+    /// the new root in the descriptor. Until then the descriptor on disk still describes the
+    /// tree as of the last commit, so a crash midway loses the changes rather than tearing the
+    /// tree. Sibling links are no longer kept up to date, so range scans find neighbouring
+    /// leaves from the root instead.
+    ///
+    /// The mode is recorded in the descriptor and cannot be turned off again.
+    pub fn set_copy_on_write(&mut self) -> Result<(), BPTreeError> {
+        if self.is_block_manager() {
+            return Err(BPTreeError::IllegalUse);
+        }
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        self.cow = true;
+        self.commit()
+    }
+
+    pub fn is_copy_on_write(&self) -> bool {
+        self.cow
+    }
+
+    /// Returns a read-only handle on the current contents of a copy-on-write tree. Later
+    /// changes to this tree copy the nodes they touch, so the snapshot keeps seeing the same
+    /// entries.
+    ///
+    /// The snapshot is recorded in the descriptor, which commits the tree, and holds a reference
+    /// to its root until it is passed to `release_snapshot`. Dropping the handle keeps it;
+    /// `snapshots` hands it out again, also after the tree is reopened.
+    pub fn snapshot(&mut self) -> Result<Self, BPTreeError> {
+        if !self.cow {
+            return Err(BPTreeError::IllegalUse);
+        }
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        if self.desc_block.is_some() && self.snapshots.len() >= self.max_snapshots() {
+            return Err(BPTreeError::TooManySnapshots);
+        }
+        if let Some(root_block) = self.root_block {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(root_block)?;
+            let mut block = block.get();
+            NodeHeader::from_bytes_mut(&mut block)?.add_ref();
+        }
+        let record = SnapshotRecord {
+            generation: self.generation.into(),
+            root: self.root_block.unwrap_or(u64::MAX).into(),
+            first_leaf: self.first_leaf.into(),
+            height: self.height.into(),
+            num_keys: self.num_keys.into(),
+        };
+        self.snapshots.push(record);
+        self.commit()?;
+        Ok(self.open_snapshot(&record))
+    }
+
+    /// Read-only handles on every snapshot the tree still keeps, oldest first.
+    pub fn snapshots(&self) -> Vec<Self> {
+        self.snapshots
+            .iter()
+            .map(|record| self.open_snapshot(record))
+            .collect()
+    }
+
+    fn open_snapshot(&self, record: &SnapshotRecord) -> Self {
+        let mut snapshot = Self::with_parts(self.io_context.clone(), self.allocator.clone(), None);
+        snapshot.root_block = Some(record.root.get()).filter(|&root| root != u64::MAX);
+        snapshot.first_leaf = record.first_leaf.get();
+        snapshot.height = record.height.get();
+        snapshot.num_keys = record.num_keys.get();
+        snapshot.cow = true;
+        snapshot.read_only = true;
+        snapshot.tree_id = self.tree_id;
+        snapshot.generation = record.generation.get();
+        snapshot
+    }
+
+    pub fn is_snapshot(&self) -> bool {
+        self.read_only
+    }
+
+    /// Gives up `snapshot`, dropping it from the descriptor and freeing the nodes no other tree
+    /// or snapshot still points at. This commits the tree.
+    pub fn release_snapshot(&mut self, snapshot: Self) -> Result<(), BPTreeError> {
+        if !snapshot.read_only || self.read_only {
+            return Err(BPTreeError::IllegalUse);
+        }
+        let root = snapshot.root_block.unwrap_or(u64::MAX);
+        let idx = self
+            .snapshots
+            .iter()
+            .position(|record| {
+                record.generation.get() == snapshot.generation && record.root.get() == root
+            })
+            .ok_or(BPTreeError::IllegalUse)?;
+        self.snapshots.remove(idx);
+        if let Some(root_block) = snapshot.root_block {
+            self.retired.push(root_block);
+        }
+        self.commit()
+    }
+
+    /// How many snapshots fit in the descriptor block after the descriptor itself.
+    fn max_snapshots(&self) -> usize {
+        let block_size = self.io_context.borrow().get_disk_block_size() as usize;
+        (block_size - size_of::<TreeDescriptor>()) / size_of::<SnapshotRecord>()
+    }
+
+    /// Leaves hold `m - 1` keys and values at most; internal nodes store a `Child` in each
+    /// value slot, so each slot is at least that wide.
+    fn with_parts(
+        ioc: Rc<RefCell<IOContext<D, C>>>,
+        allocator: Option<Rc<RefCell<A>>>,
+        take_block: Option<TakeBlock<Self>>,
+    ) -> Self {
+        let slot = size_of::<K>() + size_of::<V>().max(size_of::<Child>());
+        let m =
+            (ioc.borrow_mut().get_disk_block_size() - size_of::<NodeHeader>() as u64) / slot as u64;
         Self {
-            io_context: ioc.clone(),
+            io_context: ioc,
+            desc_block: None,
             root_block: None,
-            is_block_manager: false,
-            allocator: Some(allocator),
             first_leaf: u64::MAX,
-            m: (ioc.borrow_mut().get_disk_block_size() - size_of::<NodeHeader>() as u64) / 16,
-        }
-    }
-
-    // pub fn try_new_with_block(
-    //     block_idx: u64,
-    //     ioc: Rc<RefCell<IOContext<D, C>>>,
-    //     allocator: Rc<RefCell<A>>,
-    // ) -> Result<Self, BPTreeError> {
-    //     let m = (ioc.borrow_mut().get_disk_block_size() - size_of::<NodeHeader>() as u64) / 16;
-    //     {
-    //         let mut ioc = ioc.borrow_mut();
-    //         let block = ioc.get_mut(block_idx)?;
-    //         let mut block = block.get();
-    //         let nodeview = NodeViewMut::get_from_bytes(&mut block, m)?; // 下一行不是改结构体的字段了吗，为什么不用标记mut
-    //         *nodeview.header = NodeHeader::new(true, 0);
-    //     };
-    //     Ok(Self {
-    //         io_context: ioc.clone(),
-    //         root_block: Some(block_idx),
-    //         is_block_manager: false,
-    //         allocator: Some(allocator),
-    //         first_leaf: block_idx,
-    //         m,
-    //     })
-    // }
-
-    pub fn insert(&mut self, key: u64, val: u64) -> Result<(), BPTreeError> {
-        if let Some(root_block) = self.root_block {
-            #[allow(unused_assignments)]
-            let mut needs_split = false;
-            {
-                let mut ioc = self.io_context.borrow_mut();
-                let block = ioc.get_mut(root_block)?;
-                let mut block = block.get();
-                let nodeview = NodeViewMut::get_from_bytes(&mut block, self.m)?;
-                needs_split = nodeview.header.num_keys.get() >= self.m - 1;
-            };
-            if needs_split {
-                let new_root = self.alloc()?;
-                {
-                    let mut ioc = self.io_context.borrow_mut();
-                    let new_block = ioc.get_mut(new_root)?;
-                    let mut new_block = new_block.get();
-                    let new_root_view = NodeViewMut::get_from_bytes(&mut new_block, self.m)?;
-                    *new_root_view.header = NodeHeader::new(false, 0);
-                    new_root_view.vals[0] = root_block.into();
-                }
-                self.split_node(new_root, root_block)?;
-                self.root_block = Some(new_root);
+            height: 0,
+            num_keys: 0,
+            allocator,
+            m,
+            take_block,
+            pending_free: Vec::new(),
+            emptied: Vec::new(),
+            fit_hint: None,
+            free_blocks: 0,
+            cow: false,
+            retired: Vec::new(),
+            reserved: Vec::new(),
+            snapshots: Vec::new(),
+            read_only: false,
+            tree_id: u64::MAX,
+            generation: 0,
+            _items: PhantomData,
+        }
+    }
+
+    fn is_block_manager(&self) -> bool {
+        self.take_block.is_some()
+    }
+
+    fn read_descriptor(&mut self, desc_block: u64) -> Result<(), BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(desc_block)?;
+        let block = block.get();
+        let (desc, rest) = TreeDescriptor::ref_from_prefix(&block)
+            .map_err(|_| BPTreeError::BadDescriptor { block: desc_block })?;
+        if desc.magic.get() != TREE_MAGIC {
+            return Err(BPTreeError::BadDescriptor { block: desc_block });
+        }
+        let (snapshots, _) =
+            <[SnapshotRecord]>::ref_from_prefix_with_elems(rest, desc.snapshots.get() as usize)
+                .map_err(|_| BPTreeError::BadDescriptor { block: desc_block })?;
+        if desc.fanout.get() != self.m {
+            return Err(BPTreeError::FanoutMismatch {
+                found: desc.fanout.get(),
+                expected: self.m,
+            });
+        }
+        self.desc_block = Some(desc_block);
+        self.tree_id = desc_block;
+        self.generation = desc.generation.get();
+        self.root_block = Some(desc.root.get()).filter(|&root| root != u64::MAX);
+        self.first_leaf = desc.first_leaf.get();
+        self.height = desc.height.get();
+        self.num_keys = desc.num_keys.get();
+        self.free_blocks = desc.free_blocks.get();
+        self.cow = desc.flags.get() & TREE_COW != 0;
+        self.snapshots = snapshots.to_vec();
+        Ok(())
+    }
+
+    /// Brings the descriptor up to date after a change. Copy-on-write trees leave it, and the
+    /// nodes it still points at, alone until `commit`.
+    fn write_descriptor(&mut self) -> Result<(), BPTreeError> {
+        if self.cow {
+            return Ok(());
+        }
+        self.commit()
+    }
+
+    /// Writes the root, first leaf and counters back to the descriptor block, if there is one,
+    /// and moves on to the next generation.
+    ///
+    /// Copy-on-write trees only change on disk here, however many changes came before: the
+    /// nodes they wrote are made durable before the descriptor that points at them, and the
+    /// descriptor before the nodes it no longer points at are released and possibly reused.
+    /// Changes since the last commit are lost if the tree is reopened without one. Nodes copied
+    /// since the last commit are changed in place until the next one, so batching changes
+    /// between commits also saves copying their paths again. Other trees already write the
+    /// descriptor on every change.
+    pub fn commit(&mut self) -> Result<(), BPTreeError> {
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        let durable = self.cow && self.desc_block.is_some();
+        if durable {
+            self.io_context.borrow_mut().barrier()?;
+        }
+        self.generation += 1;
+        if let Some(desc_block) = self.desc_block {
+            self.write_descriptor_block(desc_block)?;
+        }
+        if durable {
+            self.io_context.borrow_mut().barrier()?;
+        }
+        for block_idx in std::mem::take(&mut self.retired) {
+            self.release(block_idx)?;
+        }
+        Ok(())
+    }
+
+    fn write_descriptor_block(&mut self, desc_block: u64) -> Result<(), BPTreeError> {
+        let desc = TreeDescriptor {
+            magic: TREE_MAGIC.into(),
+            root: self.root_block.unwrap_or(u64::MAX).into(),
+            first_leaf: self.first_leaf.into(),
+            height: self.height.into(),
+            num_keys: self.num_keys.into(),
+            fanout: self.m.into(),
+            flags: if self.cow { TREE_COW } else { 0 }.into(),
+            generation: self.generation.into(),
+            snapshots: (self.snapshots.len() as u64).into(),
+            free_blocks: self.free_blocks.into(),
+        };
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get_mut(desc_block)?;
+        let mut block = block.get();
+        let (head, rest) = block.split_at_mut(size_of::<TreeDescriptor>());
+        desc.write_to(head)
+            .map_err(|_| BPTreeError::BadDescriptor { block: desc_block })?;
+        self.snapshots
+            .as_slice()
+            .write_to_prefix(rest)
+            .map_err(|_| BPTreeError::BadDescriptor { block: desc_block })?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, key: K, val: V) -> Result<(), BPTreeError> {
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        self.cow_root()?;
+        if let Some(root_block) = self.split_root()? {
+            if self.insert_node(root_block, key, val)? {
+                self.num_keys += 1;
             }
-            self.insert_node(self.root_block.unwrap(), key, val)
         } else {
             let new_block_idx = self.alloc()?;
 
             self.root_block = Some(new_block_idx);
             self.first_leaf = new_block_idx;
+            self.height = 1;
+            self.num_keys = 1;
             let new_block = self.io_context.borrow_mut().get_mut(new_block_idx)?;
             let mut new_block_guard = new_block.get();
-            let new_node = NodeViewMut::get_from_bytes(&mut new_block_guard, self.m)?;
-            *new_node.header = NodeHeader::new(true, 1);
-            new_node.keys[0] = key.into();
-            new_node.vals[0] = val.into();
-            Ok(())
+            let new_node =
+                NodeViewMut::<K, V>::init(&mut new_block_guard, self.m, self.new_header(0, 1))?;
+            new_node.keys[0] = key;
+            new_node.vals[0] = val;
         }
+        self.write_descriptor()
     }
 
-    pub fn get(&self, key: u64) -> Result<Option<u64>, BPTreeError> {
-        let mut cur_block: u64;
-        if let Some(root_block) = self.root_block {
-            cur_block = root_block;
-        } else {
+    /// Splits a full root under a new one, so a descent can make room for one more key. Returns
+    /// the root.
+    fn split_root(&mut self) -> Result<Option<u64>, BPTreeError> {
+        let Some(root_block) = self.root_block else {
             return Ok(None);
+        };
+        let needs_split = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(root_block)?;
+            let block = block.get();
+            NodeHeader::from_bytes(&block)?.num_keys.get() >= self.m - 1
+        };
+        if needs_split {
+            let new_root = self.alloc_node_near(root_block)?;
+            {
+                let mut ioc = self.io_context.borrow_mut();
+                let new_block = ioc.get_mut(new_root)?;
+                let mut new_block = new_block.get();
+                let header = self.new_header(self.height as u16, 0);
+                let new_root_view = NodeViewMut::<K, Child>::init(&mut new_block, self.m, header)?;
+                new_root_view.vals[0] = Child::new(root_block, self.num_keys);
+            }
+            self.split_node(new_root, root_block)?;
+            self.root_block = Some(new_root);
+            self.height += 1;
+        }
+        Ok(self.root_block)
+    }
+
+    /// Hands the value stored under `key`, if any, to `f` and stores what it returns: `Some`
+    /// inserts or overwrites, `None` removes. Returns the previous value.
+    ///
+    /// This covers insert-if-absent, compare-and-swap and read-modify-write in one descent. Full
+    /// nodes are split on the way down as for `insert`; if the entry is removed, nodes left
+    /// underfull are refilled on the way back up.
+    pub fn update(
+        &mut self,
+        key: K,
+        f: impl FnOnce(Option<V>) -> Option<V>,
+    ) -> Result<Option<V>, BPTreeError> {
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
         }
+        self.cow_root()?;
+        let Some(mut block_idx) = self.split_root()? else {
+            if let Some(val) = f(None) {
+                self.insert(key, val)?;
+            }
+            return Ok(None);
+        };
 
+        let mut path = Vec::new();
         loop {
+            let (idx, child, needs_split) = {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get(block_idx)?;
+                let block = block.get();
+                if NodeHeader::from_bytes(&block)?.is_leaf == 1 {
+                    break;
+                }
+                let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+                let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
+                    .partition_point(|x| *x <= key);
+                let child = nodeview.vals[idx].block.get();
+                let child_block = ioc.get(child)?;
+                let child_block = child_block.get();
+                (
+                    idx,
+                    child,
+                    NodeHeader::from_bytes(&child_block)?.num_keys.get() >= self.m - 1,
+                )
+            };
+            let child = self.cow_child(block_idx, child)?;
+            if needs_split {
+                self.split_node(block_idx, child)?;
+                continue;
+            }
+            path.push((block_idx, idx));
+            block_idx = child;
+        }
+
+        let (found, old) = {
             let mut ioc = self.io_context.borrow_mut();
-            let block = ioc.get(cur_block)?;
+            let block = ioc.get(block_idx)?;
             let block = block.get();
-            let nodeview = NodeView::get_from_bytes(&block, self.m)?;
+            let nodeview = NodeView::<K, V>::get_from_bytes(&block, self.m, self.check())?;
+            match nodeview.keys[..nodeview.header.num_keys.get() as usize].binary_search(&key) {
+                Ok(idx) => (Ok(idx), Some(nodeview.vals[idx])),
+                Err(idx) => (Err(idx), None),
+            }
+        };
+        let new = f(old);
+        {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let nodeview = NodeViewMut::<K, V>::get_from_bytes(&mut block, self.m, self.check())?;
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            match (found, new) {
+                (Ok(idx), Some(val)) => nodeview.vals[idx] = val,
+                (Err(idx), Some(val)) => {
+                    nodeview.keys.copy_within(idx..num_keys, idx + 1);
+                    nodeview.vals.copy_within(idx..num_keys, idx + 1);
+                    nodeview.keys[idx] = key;
+                    nodeview.vals[idx] = val;
+                    nodeview.header.num_keys += 1;
+                    self.num_keys += 1;
+                }
+                (Ok(idx), None) => {
+                    nodeview.keys[..num_keys].copy_within(idx + 1..num_keys, idx);
+                    nodeview.vals[..num_keys].copy_within(idx + 1..num_keys, idx);
+                    nodeview.header.num_keys -= 1;
+                    self.num_keys -= 1;
+                }
+                (Err(_), None) => {}
+            }
+        }
 
-            if nodeview.header.is_leaf == 1 {
-                let Ok(idx) = nodeview.keys[..nodeview.header.num_keys.get() as usize]
-                    .binary_search(&U64::new(key))
-                else {
-                    return Ok(None);
-                };
-                return Ok(Some(nodeview.vals[idx].into()));
+        if found.is_ok() != new.is_some() {
+            for &(father, idx) in path.iter().rev() {
+                self.recount(father, idx)?;
             }
+        }
+        if found.is_ok() && new.is_none() {
+            self.refill(path)?;
+            self.collapse_root()?;
+        }
+        self.write_descriptor()?;
+        Ok(old)
+    }
 
-            let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
-                .partition_point(|&x| x.get() <= key);
-            cur_block = nodeview.vals[idx].get();
+    /// Walks `path`, a list of `(father, idx)` pairs from the root down, back up and refills
+    /// each child left below `min_keys`, stopping at the first one that is not.
+    fn refill(&mut self, path: Vec<(u64, usize)>) -> Result<(), BPTreeError> {
+        for (father, idx) in path.into_iter().rev() {
+            let (num_keys, child_keys) = {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get(father)?;
+                let block = block.get();
+                let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+                let child_block = ioc.get(nodeview.vals[idx].block.get())?;
+                let child_block = child_block.get();
+                (
+                    nodeview.header.num_keys.get(),
+                    NodeHeader::from_bytes(&child_block)?.num_keys.get(),
+                )
+            };
+            if child_keys >= self.min_keys() || num_keys == 0 {
+                break;
+            }
+            self.fill_child(father, idx)?;
         }
+        Ok(())
     }
 
-    pub fn remove(&self, key: u64) -> Result<Option<u64>, BPTreeError> {
+    pub fn get(&self, key: K) -> Result<Option<V>, BPTreeError> {
         let mut cur_block: u64;
         if let Some(root_block) = self.root_block {
             cur_block = root_block;
@@ -162,236 +597,1255 @@ where
 
         loop {
             let mut ioc = self.io_context.borrow_mut();
-            let block = ioc.get_mut(cur_block)?;
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+
+            let nodeview = match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                Node::Leaf(nodeview) => {
+                    let num_keys = nodeview.header.num_keys.get() as usize;
+                    let Ok(idx) = nodeview.keys[..num_keys].binary_search(&key) else {
+                        return Ok(None);
+                    };
+                    return Ok(Some(nodeview.vals[idx]));
+                }
+                Node::Internal(nodeview) => nodeview,
+            };
+            let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
+                .partition_point(|x| *x <= key);
+            cur_block = nodeview.vals[idx].block.get();
+        }
+    }
+
+    /// Removes `key`, rebalancing on the way down so every node it leaves behind still holds at
+    /// least `min_keys` keys. Emptied nodes go back to the allocator. Nothing is touched if
+    /// `key` is absent.
+    pub fn remove(&mut self, key: K) -> Result<Option<V>, BPTreeError> {
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        if self.get(key)?.is_none() {
+            return Ok(None);
+        }
+        self.cow_root()?;
+        let Some(root_block) = self.root_block else {
+            return Ok(None);
+        };
+        let val = self.remove_node(root_block, key)?;
+        if val.is_some() {
+            self.num_keys -= 1;
+        }
+        self.collapse_root()?;
+        self.write_descriptor()?;
+        Ok(val)
+    }
+
+    fn remove_node(&mut self, block_idx: u64, key: K) -> Result<Option<V>, BPTreeError> {
+        let (idx, num_keys, child_keys) = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
             let mut block = block.get();
-            let nodeview = NodeViewMut::get_from_bytes(&mut block, self.m)?;
 
-            if nodeview.header.is_leaf == 1 {
-                let Ok(idx) = nodeview.keys[..nodeview.header.num_keys.get() as usize]
-                    .binary_search(&U64::new(key))
-                else {
+            if NodeHeader::from_bytes(&block)?.is_leaf == 1 {
+                let nodeview =
+                    NodeViewMut::<K, V>::get_from_bytes(&mut block, self.m, self.check())?;
+                let num_keys = nodeview.header.num_keys.get() as usize;
+                let Ok(idx) = nodeview.keys[..num_keys].binary_search(&key) else {
                     return Ok(None);
                 };
-                let val = nodeview.vals[idx].into();
-                let num_keys = nodeview.header.num_keys.get() as usize;
+                let val = nodeview.vals[idx];
                 nodeview.keys[..num_keys].copy_within(idx + 1..num_keys, idx);
                 nodeview.vals[..num_keys].copy_within(idx + 1..num_keys, idx);
                 nodeview.header.num_keys -= 1;
                 return Ok(Some(val));
             }
 
-            let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
-                .partition_point(|&x| x.get() <= key);
-            cur_block = nodeview.vals[idx].get();
+            let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            let idx = nodeview.keys[..num_keys].partition_point(|x| *x <= key);
+            let child_block = ioc.get(nodeview.vals[idx].block.get())?;
+            let child_block = child_block.get();
+            (
+                idx,
+                num_keys,
+                NodeHeader::from_bytes(&child_block)?.num_keys.get(),
+            )
+        };
+
+        if child_keys <= self.min_keys() && num_keys > 0 {
+            self.fill_child(block_idx, idx)?;
+        }
+        let (idx, child) = self.slot_for(block_idx, key)?;
+        let child = self.cow_child(block_idx, child)?;
+        let val = self.remove_node(child, key)?;
+        if val.is_some() {
+            self.recount(block_idx, idx)?;
         }
+        Ok(val)
     }
 
-    fn insert_node(&mut self, block_idx: u64, key: u64, val: u64) -> Result<(), BPTreeError> {
-        let (next_idx, needs_split) = {
+    /// Tops up the `idx`-th child of `father` before the delete descends into it, borrowing a
+    /// key from a sibling if one can spare it and merging with a sibling otherwise.
+    fn fill_child(&mut self, father: u64, idx: usize) -> Result<(), BPTreeError> {
+        let (num_keys, left_keys, right_keys) = {
             let mut ioc = self.io_context.borrow_mut();
-            let block = ioc.get_mut(block_idx)?;
-            let mut block = block.get();
-            let nodeview = NodeViewMut::get_from_bytes(&mut block, self.m)?;
+            let block = ioc.get(father)?;
+            let block = block.get();
+            let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+            let num_keys = nodeview.header.num_keys.get() as usize;
 
-            if nodeview.header.is_leaf == 1 {
-                let num_keys = nodeview.header.num_keys.get() as usize;
-                match nodeview.keys[..num_keys].binary_search(&U64::new(key)) {
-                    Ok(idx) => {
-                        nodeview.vals[idx] = val.into();
-                    }
-                    Err(idx) => {
-                        nodeview.keys.copy_within(idx..num_keys, idx + 1);
-                        nodeview.vals.copy_within(idx..num_keys, idx + 1);
-                        nodeview.keys[idx] = key.into();
-                        nodeview.vals[idx] = val.into();
-                        nodeview.header.num_keys += 1;
-                    }
-                }
-                return Ok(());
+            let mut sibling_keys = |i: usize| -> Result<u64, BPTreeError> {
+                let sibling = ioc.get(nodeview.vals[i].block.get())?;
+                let sibling = sibling.get();
+                Ok(NodeHeader::from_bytes(&sibling)?.num_keys.get())
             };
-
-            let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
-                .partition_point(|&x| x.get() <= key);
-
-            let next_idx = nodeview.vals[idx].get();
-            let next_block = ioc.get_mut(next_idx)?;
-            let mut next_block = next_block.get();
-            let next_nodeview = NodeViewMut::get_from_bytes(&mut next_block, self.m)?;
-
-            (next_idx, next_nodeview.header.num_keys.get() >= self.m - 1)
+            let left_keys = if idx > 0 {
+                Some(sibling_keys(idx - 1)?)
+            } else {
+                None
+            };
+            let right_keys = if idx < num_keys {
+                Some(sibling_keys(idx + 1)?)
+            } else {
+                None
+            };
+            (num_keys, left_keys, right_keys)
         };
+        debug_assert!(num_keys > 0);
 
-        if needs_split {
-            self.split_node(block_idx, next_idx)?;
-            return self.insert_node(block_idx, key, val);
+        // Both nodes involved change, and the right one of a merge is freed, so both must be
+        // copies made for this change.
+        match (left_keys, right_keys) {
+            (Some(left_keys), _) if left_keys > self.min_keys() => {
+                self.cow_children(father, idx - 1..=idx)?;
+                self.borrow_from_left(father, idx)
+            }
+            (_, Some(right_keys)) if right_keys > self.min_keys() => {
+                self.cow_children(father, idx..=idx + 1)?;
+                self.borrow_from_right(father, idx)
+            }
+            (Some(_), _) => {
+                self.cow_children(father, idx - 1..=idx)?;
+                self.merge_children(father, idx - 1)
+            }
+            (None, _) => {
+                self.cow_children(father, idx..=idx + 1)?;
+                self.merge_children(father, idx)
+            }
         }
-        self.insert_node(next_idx, key, val)
     }
 
-    fn alloc(&mut self) -> Result<u64, BPTreeError> {
-        if self.is_block_manager {
-            self.pop_first_extent()
+    fn borrow_from_left(&mut self, father: u64, idx: usize) -> Result<(), BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let father_block = ioc.get_mut(father)?;
+        let mut father_block = father_block.get();
+        let father_nodeview =
+            NodeViewMut::<K, Child>::get_from_bytes(&mut father_block, self.m, self.check())?;
+
+        let left_block = ioc.get_mut(father_nodeview.vals[idx - 1].block.get())?;
+        let mut left_block = left_block.get();
+        let child_block = ioc.get_mut(father_nodeview.vals[idx].block.get())?;
+        let mut child_block = child_block.get();
+
+        if NodeHeader::from_bytes(&child_block)?.is_leaf == 1 {
+            let left_nodeview =
+                NodeViewMut::<K, V>::get_from_bytes(&mut left_block, self.m, self.check())?;
+            let child_nodeview =
+                NodeViewMut::<K, V>::get_from_bytes(&mut child_block, self.m, self.check())?;
+            let left_keys = left_nodeview.header.num_keys.get() as usize;
+            let child_keys = child_nodeview.header.num_keys.get() as usize;
+
+            child_nodeview.keys.copy_within(..child_keys, 1);
+            child_nodeview.vals.copy_within(..child_keys, 1);
+            child_nodeview.keys[0] = left_nodeview.keys[left_keys - 1];
+            child_nodeview.vals[0] = left_nodeview.vals[left_keys - 1];
+            father_nodeview.keys[idx - 1] = child_nodeview.keys[0];
+            father_nodeview.vals[idx - 1].count -= 1;
+            father_nodeview.vals[idx].count += 1;
+            left_nodeview.header.num_keys -= 1;
+            child_nodeview.header.num_keys += 1;
         } else {
-            Ok(self.allocator.as_ref().unwrap().borrow_mut().alloc()?)
+            let left_nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut left_block, self.m, self.check())?;
+            let child_nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut child_block, self.m, self.check())?;
+            let left_keys = left_nodeview.header.num_keys.get() as usize;
+            let child_keys = child_nodeview.header.num_keys.get() as usize;
+
+            child_nodeview.keys.copy_within(..child_keys, 1);
+            child_nodeview.vals.copy_within(..child_keys + 1, 1);
+            child_nodeview.keys[0] = father_nodeview.keys[idx - 1];
+            child_nodeview.vals[0] = left_nodeview.vals[left_keys];
+            father_nodeview.keys[idx - 1] = left_nodeview.keys[left_keys - 1];
+            let moved = child_nodeview.vals[0].count;
+            father_nodeview.vals[idx - 1].count -= moved;
+            father_nodeview.vals[idx].count += moved;
+            left_nodeview.header.num_keys -= 1;
+            child_nodeview.header.num_keys += 1;
         }
+        Ok(())
     }
 
-    fn split_node(&mut self, father: u64, child: u64) -> Result<(), BPTreeError> {
-        let new_node = self.alloc()?;
-
+    fn borrow_from_right(&mut self, father: u64, idx: usize) -> Result<(), BPTreeError> {
         let mut ioc = self.io_context.borrow_mut();
         let father_block = ioc.get_mut(father)?;
         let mut father_block = father_block.get();
-        let father_nodeview = NodeViewMut::get_from_bytes(&mut father_block, self.m)?;
+        let father_nodeview =
+            NodeViewMut::<K, Child>::get_from_bytes(&mut father_block, self.m, self.check())?;
 
-        let child_block = ioc.get_mut(child)?;
+        let child_block = ioc.get_mut(father_nodeview.vals[idx].block.get())?;
         let mut child_block = child_block.get();
-        let child_nodeview = NodeViewMut::get_from_bytes(&mut child_block, self.m)?;
+        let right_block = ioc.get_mut(father_nodeview.vals[idx + 1].block.get())?;
+        let mut right_block = right_block.get();
 
-        let new_node_block = ioc.get_mut(new_node)?;
-        let mut new_node_block = new_node_block.get();
-        let new_nodeview = NodeViewMut::get_from_bytes(&mut new_node_block, self.m)?;
+        if NodeHeader::from_bytes(&child_block)?.is_leaf == 1 {
+            let child_nodeview =
+                NodeViewMut::<K, V>::get_from_bytes(&mut child_block, self.m, self.check())?;
+            let right_nodeview =
+                NodeViewMut::<K, V>::get_from_bytes(&mut right_block, self.m, self.check())?;
+            let child_keys = child_nodeview.header.num_keys.get() as usize;
+            let right_keys = right_nodeview.header.num_keys.get() as usize;
 
-        let mid = child_nodeview.header.num_keys.get() / 2;
+            child_nodeview.keys[child_keys] = right_nodeview.keys[0];
+            child_nodeview.vals[child_keys] = right_nodeview.vals[0];
+            right_nodeview.keys.copy_within(1..right_keys, 0);
+            right_nodeview.vals.copy_within(1..right_keys, 0);
+            father_nodeview.keys[idx] = right_nodeview.keys[0];
+            father_nodeview.vals[idx].count += 1;
+            father_nodeview.vals[idx + 1].count -= 1;
+            right_nodeview.header.num_keys -= 1;
+            child_nodeview.header.num_keys += 1;
+        } else {
+            let child_nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut child_block, self.m, self.check())?;
+            let right_nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut right_block, self.m, self.check())?;
+            let child_keys = child_nodeview.header.num_keys.get() as usize;
+            let right_keys = right_nodeview.header.num_keys.get() as usize;
 
-        if child_nodeview.header.is_leaf == 0 {
-            let num_left = mid;
-            let num_right = child_nodeview.header.num_keys.get() - mid - 1;
+            child_nodeview.keys[child_keys] = father_nodeview.keys[idx];
+            child_nodeview.vals[child_keys + 1] = right_nodeview.vals[0];
+            father_nodeview.keys[idx] = right_nodeview.keys[0];
+            let moved = right_nodeview.vals[0].count;
+            father_nodeview.vals[idx].count += moved;
+            father_nodeview.vals[idx + 1].count -= moved;
+            right_nodeview.keys.copy_within(1..right_keys, 0);
+            right_nodeview.vals.copy_within(1..right_keys + 1, 0);
+            right_nodeview.header.num_keys -= 1;
+            child_nodeview.header.num_keys += 1;
+        }
+        Ok(())
+    }
 
-            *new_nodeview.header = NodeHeader::new(false, num_right);
-            new_nodeview.keys[..num_right as usize].clone_from_slice(
-                &child_nodeview.keys
-                    [mid as usize + 1..child_nodeview.header.num_keys.get() as usize],
-            );
-            new_nodeview.vals[..num_right as usize + 1].clone_from_slice(
-                &child_nodeview.vals
-                    [mid as usize + 1..child_nodeview.header.num_keys.get() as usize + 1],
-            );
+    /// Folds the `idx + 1`-th child of `father` into the `idx`-th one and frees it.
+    fn merge_children(&mut self, father: u64, idx: usize) -> Result<(), BPTreeError> {
+        let right = {
+            let mut ioc = self.io_context.borrow_mut();
+            let father_block = ioc.get_mut(father)?;
+            let mut father_block = father_block.get();
+            let father_nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut father_block, self.m, self.check())?;
 
-            child_nodeview.header.num_keys = U64::new(num_left);
+            let left = father_nodeview.vals[idx].block.get();
+            let right = father_nodeview.vals[idx + 1].block.get();
 
-            let insert_idx = father_nodeview.keys[..father_nodeview.header.num_keys.get() as usize]
-                .partition_point(|&x| x.get() < child_nodeview.keys[mid as usize].get());
-            father_nodeview.keys.copy_within(
-                insert_idx..father_nodeview.header.num_keys.get() as usize,
-                insert_idx + 1,
-            );
-            father_nodeview.vals.copy_within(
-                insert_idx + 1..father_nodeview.header.num_keys.get() as usize + 1,
-                insert_idx + 2,
-            );
-            father_nodeview.header.num_keys += 1;
-            father_nodeview.keys[insert_idx] = child_nodeview.keys[mid as usize];
-            father_nodeview.vals[insert_idx + 1] = new_node.into();
-        } else {
-            let num_left = mid;
-            let num_right = child_nodeview.header.num_keys.get() - num_left;
+            let left_block = ioc.get_mut(left)?;
+            let mut left_block = left_block.get();
+            let right_block = ioc.get(right)?;
+            let right_block = right_block.get();
 
-            *new_nodeview.header = NodeHeader::new(true, num_right);
-            new_nodeview.keys[..num_right as usize]
-                .copy_from_slice(&child_nodeview.keys[mid as usize..(mid + num_right) as usize]);
-            new_nodeview.vals[..num_right as usize]
-                .copy_from_slice(&child_nodeview.vals[mid as usize..(mid + num_right) as usize]);
+            let next = if NodeHeader::from_bytes(&left_block)?.is_leaf == 1 {
+                let left_nodeview =
+                    NodeViewMut::<K, V>::get_from_bytes(&mut left_block, self.m, self.check())?;
+                let right_nodeview =
+                    NodeView::<K, V>::get_from_bytes(&right_block, self.m, self.check())?;
+                let left_keys = left_nodeview.header.num_keys.get() as usize;
+                let right_keys = right_nodeview.header.num_keys.get() as usize;
 
-            child_nodeview.header.num_keys = num_left.into();
+                left_nodeview.keys[left_keys..left_keys + right_keys]
+                    .copy_from_slice(&right_nodeview.keys[..right_keys]);
+                left_nodeview.vals[left_keys..left_keys + right_keys]
+                    .copy_from_slice(&right_nodeview.vals[..right_keys]);
+                left_nodeview.header.num_keys = ((left_keys + right_keys) as u64).into();
+                if !self.cow {
+                    left_nodeview.header.next = right_nodeview.header.next;
+                }
+                right_nodeview.header.next.get()
+            } else {
+                let left_nodeview =
+                    NodeViewMut::<K, Child>::get_from_bytes(&mut left_block, self.m, self.check())?;
+                let right_nodeview =
+                    NodeView::<K, Child>::get_from_bytes(&right_block, self.m, self.check())?;
+                let left_keys = left_nodeview.header.num_keys.get() as usize;
+                let right_keys = right_nodeview.header.num_keys.get() as usize;
 
-            let insert_idx = father_nodeview.keys[..father_nodeview.header.num_keys.get() as usize]
-                .partition_point(|&x| x.get() < new_nodeview.keys[0].get());
-            father_nodeview.keys.copy_within(
-                insert_idx..father_nodeview.header.num_keys.get() as usize,
-                insert_idx + 1,
-            );
-            father_nodeview.keys[insert_idx as usize] = new_nodeview.keys[0];
-            father_nodeview.vals.copy_within(
-                insert_idx + 1..father_nodeview.header.num_keys.get() as usize + 1,
-                insert_idx + 2,
-            );
-            father_nodeview.vals[insert_idx + 1] = new_node.into();
-            father_nodeview.header.num_keys += 1;
+                left_nodeview.keys[left_keys] = father_nodeview.keys[idx];
+                left_nodeview.keys[left_keys + 1..left_keys + 1 + right_keys]
+                    .copy_from_slice(&right_nodeview.keys[..right_keys]);
+                left_nodeview.vals[left_keys + 1..left_keys + 2 + right_keys]
+                    .copy_from_slice(&right_nodeview.vals[..right_keys + 1]);
+                left_nodeview.header.num_keys = ((left_keys + 1 + right_keys) as u64).into();
+                if !self.cow {
+                    left_nodeview.header.next = right_nodeview.header.next;
+                }
+                right_nodeview.header.next.get()
+            };
+            if !self.cow {
+                Self::link_prev(&mut ioc, next, left)?;
+            }
+
+            let merged = father_nodeview.vals[idx + 1].count;
+            father_nodeview.vals[idx].count += merged;
+            let num_keys = father_nodeview.header.num_keys.get() as usize;
+            father_nodeview.keys.copy_within(idx + 1..num_keys, idx);
+            father_nodeview
+                .vals
+                .copy_within(idx + 2..num_keys + 1, idx + 1);
+            father_nodeview.header.num_keys -= 1;
+            right
         };
-        new_nodeview.header.next = child_nodeview.header.next;
-        child_nodeview.header.next = new_node.into();
+        self.free_node(right)
+    }
 
+    /// Points the `prev` link of `block_idx` at `prev`, unless `block_idx` is the end of a chain.
+    fn link_prev(ioc: &mut IOContext<D, C>, block_idx: u64, prev: u64) -> Result<(), BPTreeError> {
+        if block_idx == u64::MAX {
+            return Ok(());
+        }
+        let block = ioc.get_mut(block_idx)?;
+        let mut block = block.get();
+        NodeHeader::from_bytes_mut(&mut block)?.prev = prev.into();
         Ok(())
     }
 
-    // TODO: 处理空盘块删除
-    pub fn pop_first_extent(&mut self) -> Result<u64, BPTreeError> {
-        if !self.is_block_manager {
-            return Err(BPTreeError::IllegalUse);
+    /// Replaces an internal root left without keys by its only child.
+    fn collapse_root(&mut self) -> Result<(), BPTreeError> {
+        while let Some(root_block) = self.root_block {
+            let child = {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get(root_block)?;
+                let block = block.get();
+                let header = NodeHeader::from_bytes(&block)?;
+                if header.is_leaf == 1 || header.num_keys.get() > 0 {
+                    return Ok(());
+                }
+                NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?.vals[0]
+                    .block
+                    .get()
+            };
+            self.root_block = Some(child);
+            self.height -= 1;
+            self.free_node(root_block)?;
         }
-        let mut cur_block = self.first_leaf;
-        loop {
-            let mut ioc = self.io_context.borrow_mut();
-            let block = ioc.get_mut(cur_block)?;
-            let mut block = block.get();
-            let nodeview = NodeViewMut::get_from_bytes(&mut block, self.m)?;
+        Ok(())
+    }
 
-            for i in 0..nodeview.header.num_keys.get() as usize {
-                if nodeview.vals[i].get() == 0 {
-                    continue;
-                }
+    /// Index and block of the child of `block_idx` that `key` routes to.
+    fn slot_for(&self, block_idx: u64, key: K) -> Result<(usize, u64), BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+        let idx =
+            nodeview.keys[..nodeview.header.num_keys.get() as usize].partition_point(|x| *x <= key);
+        Ok((idx, nodeview.vals[idx].block.get()))
+    }
 
-                let new_block_id = nodeview.keys[i].get();
-                nodeview.keys[i] += 1;
-                nodeview.vals[i] -= 1;
+    /// What every node read by this tree is checked against.
+    fn check(&self) -> NodeCheck {
+        NodeCheck {
+            tree_id: self.tree_id,
+            generation: self.generation,
+        }
+    }
 
-                let num_keys = nodeview.header.num_keys.get() as usize;
-                nodeview.keys.copy_within(i..num_keys, 0);
-                nodeview.vals.copy_within(i..num_keys, 0);
-                nodeview.header.num_keys -= U64::new(i as u64);
+    /// Header for a node of this tree written now.
+    fn new_header(&self, level: u16, num_keys: u64) -> NodeHeader {
+        let header = NodeHeader::new(level, num_keys, self.tree_id, self.generation);
+        if level == 0 {
+            header.with_layout::<K, V>(self.m)
+        } else {
+            header.with_layout::<K, Child>(self.m)
+        }
+    }
 
-                return Ok(new_block_id);
-            }
-            nodeview.header.num_keys = 0.into();
-            cur_block = nodeview.header.next.get();
+    /// Non-root nodes never drop below this many keys once a delete has touched them.
+    fn min_keys(&self) -> u64 {
+        (self.m - 2) / 2
+    }
+
+    /// Entry with the greatest key `<= key`.
+    pub fn floor(&self, key: K) -> Result<Option<(K, V)>, BPTreeError> {
+        match self.root_block {
+            Some(root_block) => self.floor_in(root_block, key),
+            None => Ok(None),
         }
     }
 
-    // pub fn insert_extent(&)
+    /// Entry with the smallest key `>= key`.
+    pub fn ceiling(&self, key: K) -> Result<Option<(K, V)>, BPTreeError> {
+        match self.root_block {
+            Some(root_block) => self.ceiling_in(root_block, key),
+            None => Ok(None),
+        }
+    }
 
-    pub fn get_m(&self) -> u64 {
-        self.m
+    /// First entry whose key is not less than `key`; the same lookup as `ceiling`, under the
+    /// name range scans usually start from.
+    pub fn lower_bound(&self, key: K) -> Result<Option<(K, V)>, BPTreeError> {
+        self.ceiling(key)
     }
-}
 
-impl<D, C> BPTree<D, C, NoneAllocator>
-where
-    D: BlockDevice,
-    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
-{
-    pub fn new_as_block_manager(
-        io_context: Rc<RefCell<IOContext<D, C>>>,
-        beg_block: u64,
-    ) -> Result<Self, BPTreeError> {
-        let m: u64;
-        {
-            let mut ioc = io_context.borrow_mut();
-            m = (ioc.get_disk_block_size() - size_of::<NodeHeader>() as u64) / 16;
-            let block = ioc.get_mut(beg_block)?;
-            let mut block = block.get();
-            let nodeview = NodeViewMut::get_from_bytes(&mut block, m)?;
-            *nodeview.header = NodeHeader::new(true, 1);
-            nodeview.keys[0] = U64::new(beg_block + 1);
-            nodeview.vals[0] =
-                U64::new(ioc.get_disk_capacity() / ioc.get_disk_block_size() - beg_block - 1);
-        }
-        Ok(Self {
-            io_context,
-            root_block: Some(beg_block),
-            is_block_manager: true,
-            allocator: None,
-            first_leaf: beg_block,
-            m,
-        })
+    /// Entry with the smallest key.
+    pub fn first(&self) -> Result<Option<(K, V)>, BPTreeError> {
+        self.range(..).next().transpose()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::{
-        block_allocator::{bptree_allocator::BPTreeAllocator, none_allocator::NoneAllocator},
-        block_device::mem_disk::MemDisk,
+    /// Entry with the greatest key.
+    pub fn last(&self) -> Result<Option<(K, V)>, BPTreeError> {
+        self.range(..).next_back().transpose()
+    }
+
+    /// Iterates over the entries whose keys fall in `bounds`, in key order.
+    pub fn range(&self, bounds: impl RangeBounds<K>) -> Range<'_, D, C, A, K, V> {
+        Range::new(self, bounds)
+    }
+
+    /// Returns whether `key` was not in the tree before.
+    fn insert_node(&mut self, block_idx: u64, key: K, val: V) -> Result<bool, BPTreeError> {
+        let (idx, next_idx, needs_split) = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+
+            if NodeHeader::from_bytes(&block)?.is_leaf == 1 {
+                let nodeview =
+                    NodeViewMut::<K, V>::get_from_bytes(&mut block, self.m, self.check())?;
+                let num_keys = nodeview.header.num_keys.get() as usize;
+                return match nodeview.keys[..num_keys].binary_search(&key) {
+                    Ok(idx) => {
+                        nodeview.vals[idx] = val;
+                        Ok(false)
+                    }
+                    Err(idx) => {
+                        nodeview.keys.copy_within(idx..num_keys, idx + 1);
+                        nodeview.vals.copy_within(idx..num_keys, idx + 1);
+                        nodeview.keys[idx] = key;
+                        nodeview.vals[idx] = val;
+                        nodeview.header.num_keys += 1;
+                        Ok(true)
+                    }
+                };
+            };
+
+            let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+            let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
+                .partition_point(|x| *x <= key);
+
+            let next_idx = nodeview.vals[idx].block.get();
+            let next_block = ioc.get(next_idx)?;
+            let next_block = next_block.get();
+
+            (
+                idx,
+                next_idx,
+                NodeHeader::from_bytes(&next_block)?.num_keys.get() >= self.m - 1,
+            )
+        };
+
+        let next_idx = self.cow_child(block_idx, next_idx)?;
+        if needs_split {
+            self.split_node(block_idx, next_idx)?;
+            return self.insert_node(block_idx, key, val);
+        }
+        let added = self.insert_node(next_idx, key, val)?;
+        if added {
+            self.recount(block_idx, idx)?;
+        }
+        Ok(added)
+    }
+
+    fn alloc(&mut self) -> Result<u64, BPTreeError> {
+        if let Some(block_idx) = self.reserved.pop() {
+            return Ok(block_idx);
+        }
+        match self.take_block {
+            Some(take_block) => take_block(self, 0),
+            None => Ok(self.allocator.as_ref().unwrap().borrow_mut().alloc()?),
+        }
+    }
+
+    fn alloc_node_near(&mut self, goal: u64) -> Result<u64, BPTreeError> {
+        if let Some(block_idx) = self.reserved.pop() {
+            return Ok(block_idx);
+        }
+        match self.take_block {
+            Some(take_block) => take_block(self, goal),
+            None => Ok(self
+                .allocator
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .alloc_near(goal)?),
+        }
+    }
+
+    fn free_node(&mut self, block_idx: u64) -> Result<(), BPTreeError> {
+        if self.is_block_manager() {
+            self.pending_free.push(block_idx);
+        } else {
+            self.allocator
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .free(block_idx)?;
+        }
+        Ok(())
+    }
+
+    /// Copy-on-write trees only: makes sure the root can be changed in place.
+    fn cow_root(&mut self) -> Result<(), BPTreeError> {
+        if let Some(root_block) = self.root_block {
+            self.root_block = Some(self.cow_node(root_block)?);
+        }
+        Ok(())
+    }
+
+    /// Copy-on-write trees only: makes sure `child` can be changed in place, pointing `father`
+    /// at the copy if one is made. `father` must itself be changeable in place.
+    fn cow_child(&mut self, father: u64, child: u64) -> Result<u64, BPTreeError> {
+        let copy = self.cow_node(child)?;
+        if copy != child {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(father)?;
+            let mut block = block.get();
+            let nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut block, self.m, self.check())?;
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            let slot = nodeview.vals[..=num_keys]
+                .iter_mut()
+                .find(|slot| slot.block.get() == child)
+                .ok_or(BPTreeError::Corrupted { block: father })?;
+            slot.block = copy.into();
+        }
+        Ok(copy)
+    }
+
+    fn cow_children(
+        &mut self,
+        father: u64,
+        idxs: std::ops::RangeInclusive<usize>,
+    ) -> Result<(), BPTreeError> {
+        for idx in idxs {
+            let child = self.child_at(father, idx)?;
+            self.cow_child(father, child)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `block_idx` if it can be changed in place, or else a copy of it in a fresh block.
+    ///
+    /// Only nodes written since the last commit and not shared with a snapshot are changed in
+    /// place; anything older may still be reached from the descriptor on disk. The copy adds a
+    /// reference to every child it points at, and the original is retired until the next
+    /// commit.
+    fn cow_node(&mut self, block_idx: u64) -> Result<u64, BPTreeError> {
+        if !self.cow {
+            return Ok(block_idx);
+        }
+        let children = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let header = NodeHeader::from_bytes(&block)?;
+            if header.generation.get() == self.generation && !header.is_shared() {
+                return Ok(block_idx);
+            }
+            if header.is_leaf == 1 {
+                Vec::new()
+            } else {
+                let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+                nodeview.vals[..=header.num_keys.get() as usize].to_vec()
+            }
+        };
+
+        let copy = self.alloc_node_near(block_idx)?;
+        self.retired.push(block_idx);
+        let mut ioc = self.io_context.borrow_mut();
+        let bytes = {
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            block.to_vec()
+        };
+        {
+            let block = ioc.get_mut(copy)?;
+            let mut block = block.get();
+            block.copy_from_slice(&bytes);
+            let mut header = NodeHeader::from_bytes_mut(&mut block)?;
+            header.refs = 1.into();
+            header.generation = self.generation.into();
+        }
+        for child in children {
+            let block = ioc.get_mut(child.block.get())?;
+            let mut block = block.get();
+            NodeHeader::from_bytes_mut(&mut block)?.add_ref();
+        }
+        if block_idx == self.first_leaf {
+            self.first_leaf = copy;
+        }
+        Ok(copy)
+    }
+
+    /// Drops one reference to `block_idx`, freeing it and releasing its children once none are
+    /// left.
+    fn release(&mut self, block_idx: u64) -> Result<(), BPTreeError> {
+        let children = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            if NodeHeader::from_bytes_mut(&mut block)?.drop_ref() > 0 {
+                return Ok(());
+            }
+            if NodeHeader::from_bytes(&block)?.is_leaf == 1 {
+                Vec::new()
+            } else {
+                let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+                nodeview.vals[..=nodeview.header.num_keys.get() as usize].to_vec()
+            }
+        };
+        self.free_node(block_idx)?;
+        for child in children {
+            self.release(child.block.get())?;
+        }
+        Ok(())
+    }
+
+    fn split_node(&mut self, father: u64, child: u64) -> Result<(), BPTreeError> {
+        let new_node = self.alloc_node_near(child)?;
+
+        let mut ioc = self.io_context.borrow_mut();
+        let father_block = ioc.get_mut(father)?;
+        let mut father_block = father_block.get();
+        let father_nodeview =
+            NodeViewMut::<K, Child>::get_from_bytes(&mut father_block, self.m, self.check())?;
+
+        let child_block = ioc.get_mut(child)?;
+        let mut child_block = child_block.get();
+        let new_node_block = ioc.get_mut(new_node)?;
+        let mut new_node_block = new_node_block.get();
+
+        let num_keys = NodeHeader::from_bytes(&child_block)?.num_keys.get();
+        let mid = num_keys / 2;
+
+        let (separator, left_count, right_count) = if NodeHeader::from_bytes(&child_block)?.is_leaf
+            == 0
+        {
+            let child_nodeview =
+                NodeViewMut::<K, Child>::get_from_bytes(&mut child_block, self.m, self.check())?;
+            let num_right = num_keys - mid - 1;
+            let header = self.new_header(child_nodeview.header.level.get(), num_right);
+            let new_nodeview = NodeViewMut::<K, Child>::init(&mut new_node_block, self.m, header)?;
+            new_nodeview.keys[..num_right as usize]
+                .copy_from_slice(&child_nodeview.keys[mid as usize + 1..num_keys as usize]);
+            new_nodeview.vals[..num_right as usize + 1]
+                .copy_from_slice(&child_nodeview.vals[mid as usize + 1..num_keys as usize + 1]);
+            child_nodeview.header.num_keys = U64::new(mid);
+            (
+                child_nodeview.keys[mid as usize],
+                Self::count_children(&child_nodeview.vals[..=mid as usize]),
+                Self::count_children(&new_nodeview.vals[..=num_right as usize]),
+            )
+        } else {
+            let child_nodeview =
+                NodeViewMut::<K, V>::get_from_bytes(&mut child_block, self.m, self.check())?;
+            let num_right = num_keys - mid;
+            let new_nodeview = NodeViewMut::<K, V>::init(
+                &mut new_node_block,
+                self.m,
+                self.new_header(0, num_right),
+            )?;
+            new_nodeview.keys[..num_right as usize]
+                .copy_from_slice(&child_nodeview.keys[mid as usize..num_keys as usize]);
+            new_nodeview.vals[..num_right as usize]
+                .copy_from_slice(&child_nodeview.vals[mid as usize..num_keys as usize]);
+            child_nodeview.header.num_keys = mid.into();
+            (new_nodeview.keys[0], mid, num_right)
+        };
+
+        let father_keys = father_nodeview.header.num_keys.get() as usize;
+        let insert_idx = father_nodeview.keys[..father_keys].partition_point(|x| *x < separator);
+        father_nodeview
+            .keys
+            .copy_within(insert_idx..father_keys, insert_idx + 1);
+        father_nodeview
+            .vals
+            .copy_within(insert_idx + 1..father_keys + 1, insert_idx + 2);
+        father_nodeview.keys[insert_idx] = separator;
+        father_nodeview.vals[insert_idx].count = left_count.into();
+        father_nodeview.vals[insert_idx + 1] = Child::new(new_node, right_count);
+        father_nodeview.header.num_keys += 1;
+
+        if !self.cow {
+            let mut child_header = NodeHeader::from_bytes_mut(&mut child_block)?;
+            let mut new_header = NodeHeader::from_bytes_mut(&mut new_node_block)?;
+            new_header.next = child_header.next;
+            new_header.prev = child.into();
+            child_header.next = new_node.into();
+            Self::link_prev(&mut ioc, new_header.next.get(), new_node)?;
+        }
+
+        Ok(())
+    }
+
+    fn leaf_for(&self, key: K) -> Result<u64, BPTreeError> {
+        let mut cur_block = self.root_block.ok_or(BPTreeError::EmptyTree)?;
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let Node::Internal(nodeview) =
+                Node::<K, V>::get_from_bytes(&block, self.m, self.check())?
+            else {
+                return Ok(cur_block);
+            };
+            let idx = nodeview.keys[..nodeview.header.num_keys.get() as usize]
+                .partition_point(|x| *x <= key);
+            cur_block = nodeview.vals[idx].block.get();
+        }
+    }
+
+    /// Greatest entry with a key `<= key` in the subtree rooted at `block_idx`.
+    fn floor_in(&self, block_idx: u64, key: K) -> Result<Option<(K, V)>, BPTreeError> {
+        let idx = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let nodeview = match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                Node::Leaf(nodeview) => {
+                    let num_keys = nodeview.header.num_keys.get() as usize;
+                    let idx = nodeview.keys[..num_keys].partition_point(|x| *x <= key);
+                    return Ok(idx
+                        .checked_sub(1)
+                        .map(|i| (nodeview.keys[i], nodeview.vals[i])));
+                }
+                Node::Internal(nodeview) => nodeview,
+            };
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            nodeview.keys[..num_keys].partition_point(|x| *x <= key)
+        };
+        for i in (0..=idx).rev() {
+            let child = self.child_at(block_idx, i)?;
+            if let Some(entry) = self.floor_in(child, key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Smallest entry with a key `>= key` in the subtree rooted at `block_idx`.
+    fn ceiling_in(&self, block_idx: u64, key: K) -> Result<Option<(K, V)>, BPTreeError> {
+        let (idx, num_keys) = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let nodeview = match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                Node::Leaf(nodeview) => {
+                    let num_keys = nodeview.header.num_keys.get() as usize;
+                    let idx = nodeview.keys[..num_keys].partition_point(|x| *x < key);
+                    return Ok((idx < num_keys).then(|| (nodeview.keys[idx], nodeview.vals[idx])));
+                }
+                Node::Internal(nodeview) => nodeview,
+            };
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            (
+                nodeview.keys[..num_keys].partition_point(|x| *x <= key),
+                num_keys,
+            )
+        };
+        for i in idx..=num_keys {
+            let child = self.child_at(block_idx, i)?;
+            if let Some(entry) = self.ceiling_in(child, key)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    fn child_at(&self, block_idx: u64, idx: usize) -> Result<u64, BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+        Ok(nodeview.vals[idx].block.get())
+    }
+
+    /// Keys stored under `children`.
+    fn count_children(children: &[Child]) -> u64 {
+        children.iter().map(|child| child.count.get()).sum()
+    }
+
+    /// Keys stored in the subtree rooted at `block_idx`, as recorded in the node itself.
+    fn subtree_count(&self, block_idx: u64) -> Result<u64, BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        Ok(
+            match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                Node::Leaf(nodeview) => nodeview.header.num_keys.get(),
+                Node::Internal(nodeview) => {
+                    Self::count_children(&nodeview.vals[..=nodeview.header.num_keys.get() as usize])
+                }
+            },
+        )
+    }
+
+    /// Brings the count `father` keeps for its `idx`-th child in line with the child.
+    fn recount(&mut self, father: u64, idx: usize) -> Result<(), BPTreeError> {
+        let child = self.child_at(father, idx)?;
+        let count = self.subtree_count(child)?;
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get_mut(father)?;
+        let mut block = block.get();
+        NodeViewMut::<K, Child>::get_from_bytes(&mut block, self.m, self.check())?.vals[idx]
+            .count = count.into();
+        Ok(())
+    }
+
+    fn rightmost_leaf(&self) -> Result<u64, BPTreeError> {
+        self.edge_leaf(self.root_block.unwrap_or(u64::MAX), true)
+    }
+
+    /// Leaf after the one whose last key is `last_key`. That is the `next` link unless the tree
+    /// is copy-on-write, in which case it is found from the root.
+    fn next_leaf(&self, next: u64, last_key: Option<K>) -> Result<u64, BPTreeError> {
+        if !self.cow {
+            return Ok(next);
+        }
+        let (Some(key), Some(mut cur_block)) = (last_key, self.root_block) else {
+            return Ok(u64::MAX);
+        };
+        let mut subtree = u64::MAX;
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let Node::Internal(nodeview) =
+                Node::<K, V>::get_from_bytes(&block, self.m, self.check())?
+            else {
+                break;
+            };
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            let idx = nodeview.keys[..num_keys].partition_point(|x| *x <= key);
+            if idx < num_keys {
+                subtree = nodeview.vals[idx + 1].block.get();
+            }
+            cur_block = nodeview.vals[idx].block.get();
+        }
+        self.edge_leaf(subtree, false)
+    }
+
+    /// Leaf before the one whose first key is `first_key`; see `next_leaf`.
+    fn prev_leaf(&self, prev: u64, first_key: Option<K>) -> Result<u64, BPTreeError> {
+        if !self.cow {
+            return Ok(prev);
+        }
+        let (Some(key), Some(mut cur_block)) = (first_key, self.root_block) else {
+            return Ok(u64::MAX);
+        };
+        let mut subtree = u64::MAX;
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let Node::Internal(nodeview) =
+                Node::<K, V>::get_from_bytes(&block, self.m, self.check())?
+            else {
+                break;
+            };
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            let idx = nodeview.keys[..num_keys].partition_point(|x| *x <= key);
+            if idx > 0 {
+                subtree = nodeview.vals[idx - 1].block.get();
+            }
+            cur_block = nodeview.vals[idx].block.get();
+        }
+        self.edge_leaf(subtree, true)
+    }
+
+    /// First or last leaf of the subtree rooted at `block_idx`.
+    fn edge_leaf(&self, mut block_idx: u64, last: bool) -> Result<u64, BPTreeError> {
+        while block_idx != u64::MAX {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let Node::Internal(nodeview) =
+                Node::<K, V>::get_from_bytes(&block, self.m, self.check())?
+            else {
+                break;
+            };
+            let idx = if last {
+                nodeview.header.num_keys.get() as usize
+            } else {
+                0
+            };
+            block_idx = nodeview.vals[idx].block.get();
+        }
+        Ok(block_idx)
+    }
+
+    pub fn get_m(&self) -> u64 {
+        self.m
+    }
+
+    /// Number of keys in the tree.
+    pub fn len(&self) -> u64 {
+        self.num_keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_keys == 0
+    }
+
+    /// Number of levels, counting the leaves; 0 for a tree without a root.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn desc_block(&self) -> Option<u64> {
+        self.desc_block
+    }
+
+    pub fn root_block(&self) -> Option<u64> {
+        self.root_block
+    }
+
+    pub fn first_leaf(&self) -> u64 {
+        self.first_leaf
+    }
+}
+
+/// Unpacks a free-space tree entry into `(start, len)`.
+fn extent((start, len): (U64, U64)) -> (u64, u64) {
+    (start.get(), len.get())
+}
+
+impl<D, C, A> BPTree<D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+{
+    /// Number of free blocks, summed over every extent as they change.
+    pub fn free_blocks(&self) -> u64 {
+        self.free_blocks
+    }
+
+    /// Hands out the lowest free block.
+    pub fn pop_first_extent(&mut self) -> Result<u64, BPTreeError> {
+        Ok(self.alloc_extent(1, 1, ExtentPolicy::FirstFit)?.0)
+    }
+
+    /// Cuts between `min_len` and `max_len` contiguous blocks from the head of a free extent
+    /// picked by `policy`, returning `(start, len)`.
+    pub fn alloc_extent(
+        &mut self,
+        min_len: u64,
+        max_len: u64,
+        policy: ExtentPolicy,
+    ) -> Result<(u64, u64), BPTreeError> {
+        if !self.is_block_manager() {
+            return Err(BPTreeError::IllegalUse);
+        }
+        if min_len == 0 || min_len > max_len {
+            return Err(BlockAllocateError::InvalidExtentLength { min_len, max_len }.into());
+        }
+        let Some((start, len)) = self.find_extent(min_len, max_len, policy)? else {
+            return Err(BlockAllocateError::NoFreeBlocks.into());
+        };
+        let taken = len.min(max_len);
+        self.remove(start.into())?;
+        if len > taken {
+            self.insert((start + taken).into(), (len - taken).into())?;
+        }
+        self.free_blocks -= taken;
+        self.settle()?;
+        Ok((start, taken))
+    }
+
+    /// Returns `len` blocks starting at `start` to the free-space tree, merging them with the
+    /// free extents directly before and after.
+    pub fn insert_extent(&mut self, start: u64, len: u64) -> Result<(), BPTreeError> {
+        if !self.is_block_manager() {
+            return Err(BPTreeError::IllegalUse);
+        }
+        self.insert_extent_inner(start, len)?;
+        self.settle()
+    }
+
+    fn insert_extent_inner(&mut self, start: u64, len: u64) -> Result<(), BPTreeError> {
+        let end = start + len;
+        let left = self
+            .floor(start.into())?
+            .map(extent)
+            .filter(|&(left_start, left_len)| left_start + left_len >= start);
+        // The extent these blocks end up in may now be long enough for a search that skipped
+        // its start before.
+        let merged_start = left.map_or(start, |(left_start, _)| left_start);
+        if let Some((_, hint_start)) = &mut self.fit_hint {
+            *hint_start = (*hint_start).min(merged_start);
+        }
+        let right = self
+            .ceiling((start + 1).into())?
+            .map(extent)
+            .filter(|&(right_start, _)| right_start <= end);
+
+        if left.is_some_and(|(left_start, left_len)| left_start + left_len > start)
+            || right.is_some_and(|(right_start, right_len)| right_start < end && right_len > 0)
+        {
+            return Err(BPTreeError::ExtentOverlap { start, len });
+        }
+
+        match (left, right) {
+            (Some((left_start, left_len)), right) => {
+                let right_len = match right {
+                    Some((right_start, right_len)) => {
+                        self.remove(right_start.into())?;
+                        right_len
+                    }
+                    None => 0,
+                };
+                // Only the length of the left extent changes. A split on the way down may cut
+                // its tail off for a node, after which it no longer reaches `start`.
+                let old = self.update(left_start.into(), |old| {
+                    old.map(|old| {
+                        if old.get() == left_len {
+                            (left_len + len + right_len).into()
+                        } else {
+                            old
+                        }
+                    })
+                })?;
+                if old != Some(left_len.into()) {
+                    self.insert(start.into(), (len + right_len).into())?;
+                }
+            }
+            (None, Some((right_start, right_len))) => {
+                self.remove(right_start.into())?;
+                self.insert(start.into(), (len + right_len).into())?;
+            }
+            (None, None) => {
+                self.insert(start.into(), len.into())?;
+            }
+        }
+        self.free_blocks += len;
+        Ok(())
+    }
+
+    /// Allocates a block for one of the free-space tree's own nodes, preferring the first free
+    /// extent at or after `goal`.
+    ///
+    /// This runs in the middle of a split, so it must not change the shape of the tree: the
+    /// block is cut from the tail of the extent, which only touches a value. Extents emptied
+    /// this way are left in place and cleaned up by `settle`.
+    fn take_block_for_node(&mut self, goal: u64) -> Result<u64, BPTreeError> {
+        let mut key = goal;
+        let start = loop {
+            match self.ceiling(key.into())?.map(extent) {
+                Some((start, 0)) => key = start + 1,
+                Some((start, _)) => break start,
+                None if key != 0 => key = 0,
+                None => return Err(BlockAllocateError::NoFreeBlocks.into()),
+            }
+        };
+
+        let leaf = self.leaf_for(start.into())?;
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get_mut(leaf)?;
+        let mut block = block.get();
+        let nodeview = NodeViewMut::<U64, U64>::get_from_bytes(&mut block, self.m, self.check())?;
+        let num_keys = nodeview.header.num_keys.get() as usize;
+        let idx = nodeview.keys[..num_keys]
+            .binary_search(&U64::new(start))
+            .map_err(|_| BPTreeError::Corrupted { block: leaf })?;
+
+        nodeview.vals[idx] -= 1;
+        self.free_blocks -= 1;
+        let len = nodeview.vals[idx].get();
+        if len == 0 {
+            self.emptied.push(start);
+        }
+        Ok(start + len)
+    }
+
+    /// Hands out the free block closest at or after `goal`, wrapping around to the lowest free
+    /// block when there is none.
+    pub fn alloc_near(&mut self, goal: u64) -> Result<u64, BPTreeError> {
+        if !self.is_block_manager() {
+            return Err(BPTreeError::IllegalUse);
+        }
+        if let Some((start, len)) = self.floor(goal.into())?.map(extent)
+            && goal < start + len
+        {
+            let len = if goal > start {
+                // Only the length of the head before `goal` changes. A split on the way down
+                // may cut the tail off for a node, possibly `goal` itself, in which case the
+                // extent is left alone and the search below picks another block.
+                let old = self.update(start.into(), |old| {
+                    old.map(|old| {
+                        if start + old.get() > goal {
+                            (goal - start).into()
+                        } else {
+                            old
+                        }
+                    })
+                })?;
+                old.map_or(0, |old| old.get())
+            } else {
+                self.remove(start.into())?;
+                len
+            };
+            if start + len > goal {
+                if start + len > goal + 1 {
+                    self.insert((goal + 1).into(), (start + len - goal - 1).into())?;
+                }
+                self.free_blocks -= 1;
+                self.settle()?;
+                return Ok(goal);
+            }
+        }
+
+        let mut key = goal;
+        loop {
+            match self.ceiling(key.into())?.map(extent) {
+                Some((start, 0)) => key = start + 1,
+                Some((start, len)) => {
+                    self.remove(start.into())?;
+                    if len > 1 {
+                        self.insert((start + 1).into(), (len - 1).into())?;
+                    }
+                    self.free_blocks -= 1;
+                    self.settle()?;
+                    return Ok(start);
+                }
+                None if key != 0 => key = 0,
+                None => return Err(BlockAllocateError::NoFreeBlocks.into()),
+            }
+        }
+    }
+
+    /// Removes extents emptied by `take_block_for_node` and returns freed nodes to the tree,
+    /// until neither leaves more work behind, then records the new free count.
+    fn settle(&mut self) -> Result<(), BPTreeError> {
+        loop {
+            if let Some(start) = self.emptied.pop() {
+                if self.get(start.into())? == Some(U64::new(0)) {
+                    self.remove(start.into())?;
+                }
+            } else if let Some(block) = self.pending_free.pop() {
+                self.insert_extent_inner(block, 1)?;
+            } else {
+                return self.write_descriptor();
+            }
+        }
+    }
+
+    /// Scans the free extents in block order for one holding at least `min_len` blocks,
+    /// skipping those that an earlier search for as many blocks or fewer found too short.
+    ///
+    /// First fit stops at the first match. Best fit prefers the smallest extent that covers
+    /// `max_len` and otherwise falls back to the largest one that covers `min_len`.
+    fn find_extent(
+        &mut self,
+        min_len: u64,
+        max_len: u64,
+        policy: ExtentPolicy,
+    ) -> Result<Option<(u64, u64)>, BPTreeError> {
+        let from = match self.fit_hint {
+            Some((hint_len, hint_start)) if hint_len <= min_len => hint_start,
+            _ => 0,
+        };
+        let mut first = None;
+        let mut best: Option<(u64, u64)> = None;
+        for entry in self.range(U64::new(from)..) {
+            let (start, len) = extent(entry?);
+            if len < min_len {
+                continue;
+            }
+            first.get_or_insert(start);
+            if policy == ExtentPolicy::FirstFit {
+                best = Some((start, len));
+                break;
+            }
+            best = match best {
+                Some((_, best_len))
+                    if (best_len >= max_len && (len < max_len || len >= best_len))
+                        || (best_len < max_len && len <= best_len) =>
+                {
+                    best
+                }
+                _ => Some((start, len)),
+            };
+            if len == max_len {
+                break;
+            }
+        }
+        self.fit_hint = Some((min_len, first.unwrap_or(u64::MAX)));
+        Ok(best)
+    }
+}
+
+impl<D, C> BPTree<D, C, NoneAllocator>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    /// Creates a fresh free-space tree with its descriptor in `desc_block` and its root leaf
+    /// right after it. Every later block starts out free.
+    pub fn create_block_manager(
+        io_context: Rc<RefCell<IOContext<D, C>>>,
+        desc_block: u64,
+    ) -> Result<Self, BPTreeError> {
+        let mut tree = Self::with_parts(io_context, None, Some(Self::take_block_for_node));
+        tree.tree_id = desc_block;
+        let root_block = desc_block + 1;
+        {
+            let mut ioc = tree.io_context.borrow_mut();
+            let blocks_count = ioc.get_disk_capacity() / ioc.get_disk_block_size();
+            let block = ioc.get_mut(root_block)?;
+            let mut block = block.get();
+            let nodeview =
+                NodeViewMut::<U64, U64>::init(&mut block, tree.m, tree.new_header(0, 1))?;
+            nodeview.keys[0] = U64::new(root_block + 1);
+            nodeview.vals[0] = U64::new(blocks_count - root_block - 1);
+            tree.free_blocks = blocks_count - root_block - 1;
+        }
+        tree.desc_block = Some(desc_block);
+        tree.root_block = Some(root_block);
+        tree.first_leaf = root_block;
+        tree.height = 1;
+        tree.num_keys = 1;
+        tree.write_descriptor()?;
+        Ok(tree)
+    }
+
+    /// Reopens a free-space tree previously written by `create_block_manager`.
+    pub fn open_block_manager(
+        io_context: Rc<RefCell<IOContext<D, C>>>,
+        desc_block: u64,
+    ) -> Result<Self, BPTreeError> {
+        let mut tree = Self::with_parts(io_context, None, Some(Self::take_block_for_node));
+        tree.read_descriptor(desc_block)?;
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        block_allocator::{
+            bptree_allocator::BPTreeAllocator,
+            test_allocator::{TestBPTreeAllocator, TestIOContext, setup},
+        },
+        block_device::mem_disk::MemDisk,
         utils::cache::lru::LRU,
     };
 
@@ -415,23 +1869,1081 @@ mod test {
             MemDisk,
             LRU<u64, Rc<RefCell<Vec<u8>>>>,
             NoneAllocator,
-        >::try_new(iocontext.clone(), 0)?));
+        >::create(iocontext.clone(), 0)?));
 
-        let mut bptree = BPTree::new(iocontext.clone(), allocator.clone());
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
         let m = bptree.get_m();
 
         for i in 0..64 * m {
             let key = pseudo_random_mapper(i);
             let val = pseudo_random_mapper(key);
-            bptree.insert(key, val)?;
+            bptree.insert(key.into(), val.into())?;
         }
 
         for i in 0..64 * m {
             let key = pseudo_random_mapper(i);
             let val = pseudo_random_mapper(key);
-            assert_eq!(bptree.get(key)?, Some(val));
+            assert_eq!(bptree.get(key.into())?, Some(val.into()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_rebalances() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        let n = 32 * bptree.get_m();
+
+        for i in 0..n {
+            let key = pseudo_random_mapper(i);
+            bptree.insert(key.into(), i.into())?;
+        }
+        for i in (0..n).step_by(2) {
+            assert_eq!(
+                bptree.remove(pseudo_random_mapper(i).into())?,
+                Some(i.into())
+            );
+        }
+        for i in 0..n {
+            let expected = (i % 2 == 1).then_some(i.into());
+            assert_eq!(bptree.get(pseudo_random_mapper(i).into())?, expected);
+        }
+        assert_eq!(bptree.verify()?, vec![]);
+        for i in (1..n).step_by(2) {
+            assert_eq!(
+                bptree.remove(pseudo_random_mapper(i).into())?,
+                Some(i.into())
+            );
+        }
+        assert_eq!(bptree.remove(pseudo_random_mapper(1).into())?, None);
+
+        let root = bptree.root_block.unwrap();
+        {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(root)?;
+            let block = block.get();
+            let nodeview =
+                NodeView::<U64, U64>::get_from_bytes(&block, bptree.get_m(), bptree.check())?;
+            assert_eq!(nodeview.header.is_leaf, 1);
+            assert_eq!(nodeview.header.num_keys.get(), 0);
+        }
+
+        // Everything but the free-space tree's descriptor and the two roots has been handed back
+        // to the allocator.
+        let mut free_blocks = 0;
+        while allocator.borrow_mut().alloc().is_ok() {
+            free_blocks += 1;
+        }
+        assert_eq!(free_blocks, 4096 - 3);
+        Ok(())
+    }
+
+    #[test]
+    fn range_scan() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(1024)?;
+
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        assert_eq!(bptree.range(..).count(), 0);
+
+        let n = 8 * bptree.get_m();
+        for i in 0..n {
+            let key = pseudo_random_mapper(i) % n * 2;
+            bptree.insert(key.into(), (key + 1).into())?;
+        }
+        let mut keys = (0..n)
+            .map(|i| pseudo_random_mapper(i) % n * 2)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        let all = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            all,
+            keys.iter()
+                .map(|&k| (k.into(), (k + 1).into()))
+                .collect::<Vec<_>>()
+        );
+
+        let (lo, hi) = (keys[keys.len() / 4], keys[keys.len() * 3 / 4]);
+        let scan = |range: Range<_, _, _>| -> Result<Vec<u64>, BPTreeError> {
+            range.map(|e| e.map(|(k, _)| k.get())).collect()
+        };
+        let key = U64::new;
+        let expect =
+            |f: &dyn Fn(u64) -> bool| keys.iter().copied().filter(|&k| f(k)).collect::<Vec<_>>();
+
+        assert_eq!(
+            scan(bptree.range(key(lo)..key(hi)))?,
+            expect(&|k| lo <= k && k < hi)
+        );
+        assert_eq!(
+            scan(bptree.range(key(lo)..=key(hi)))?,
+            expect(&|k| lo <= k && k <= hi)
+        );
+        assert_eq!(scan(bptree.range(key(lo + 1)..))?, expect(&|k| lo < k));
+        assert_eq!(
+            scan(bptree.range(..=key(hi + 1)))?,
+            expect(&|k| k <= hi + 1)
+        );
+        assert_eq!(
+            scan(bptree.range((
+                std::ops::Bound::Excluded(key(lo)),
+                std::ops::Bound::Excluded(key(hi))
+            )))?,
+            expect(&|k| lo < k && k < hi)
+        );
+        assert_eq!(scan(bptree.range(key(hi)..key(lo)))?, Vec::<u64>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn range_rev() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(1024)?;
+
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        let n = 16 * bptree.get_m();
+        for i in 0..n {
+            bptree.insert(pseudo_random_mapper(i).into(), i.into())?;
+        }
+        for i in (0..n).filter(|i| i % 3 != 0) {
+            bptree.remove(pseudo_random_mapper(i).into())?;
+        }
+
+        let forward = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+        let mut backward = bptree.range(..).rev().collect::<Result<Vec<_>, _>>()?;
+        backward.reverse();
+        assert_eq!(forward.len() as u64, n.div_ceil(3));
+        assert_eq!(forward, backward);
+
+        let (lo, hi) = (forward[10].0, forward[forward.len() - 10].0);
+        let tail = bptree
+            .range(..hi)
+            .rev()
+            .take(5)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            tail,
+            forward[forward.len() - 15..forward.len() - 10]
+                .iter()
+                .rev()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+
+        let mut range = bptree.range(lo..=hi);
+        let mut mixed = Vec::new();
+        let mut mixed_back = Vec::new();
+        loop {
+            match (range.next(), range.next_back()) {
+                (None, None) => break,
+                (front, back) => {
+                    mixed.extend(front.transpose()?);
+                    mixed_back.extend(back.transpose()?);
+                }
+            }
+        }
+        mixed.extend(mixed_back.into_iter().rev());
+        assert_eq!(mixed, forward[10..=forward.len() - 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_lookups() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(1024)?;
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        assert_eq!(bptree.first()?, None);
+        assert_eq!(bptree.floor(10.into())?, None);
+
+        let n = 8 * bptree.get_m();
+        for i in 1..=n {
+            bptree.insert((i * 10).into(), i.into())?;
+        }
+
+        let entry = |k: u64, v: u64| Some((U64::new(k), U64::new(v)));
+        assert_eq!(bptree.first()?, entry(10, 1));
+        assert_eq!(bptree.last()?, entry(n * 10, n));
+        assert_eq!(bptree.floor(5.into())?, None);
+        assert_eq!(bptree.ceiling((n * 10 + 1).into())?, None);
+        for i in 1..n {
+            assert_eq!(bptree.floor((i * 10).into())?, entry(i * 10, i));
+            assert_eq!(bptree.floor((i * 10 + 9).into())?, entry(i * 10, i));
+            assert_eq!(
+                bptree.ceiling((i * 10 + 1).into())?,
+                entry(i * 10 + 10, i + 1)
+            );
+            assert_eq!(bptree.lower_bound((i * 10).into())?, entry(i * 10, i));
+        }
+
+        // Lookups that fall on the boundary of an emptied leaf still find their neighbours.
+        for i in n / 4..n / 2 {
+            bptree.remove((i * 10).into())?;
+        }
+        assert_eq!(
+            bptree.floor((n / 2 * 10 - 1).into())?,
+            entry(n / 4 * 10 - 10, n / 4 - 1)
+        );
+        assert_eq!(
+            bptree.ceiling((n / 4 * 10).into())?,
+            entry(n / 2 * 10, n / 2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let m = BPTree::<_, _, _>::new(iocontext.clone(), allocator.clone()).get_m();
+
+        for (n, fill_factor) in [
+            (0, 1.0),
+            (1, 1.0),
+            (m - 1, 1.0),
+            (m, 1.0),
+            (37 * m + 5, 0.7),
+        ] {
+            let entries = (0..n).map(|i| (U64::new(i * 3), U64::new(i)));
+            let mut bptree = BPTree::new(iocontext.clone(), allocator.clone());
+            bptree.bulk_load(entries.clone(), fill_factor)?;
+
+            let all = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(all, entries.collect::<Vec<_>>());
+            let mut rev = bptree.range(..).rev().collect::<Result<Vec<_>, _>>()?;
+            rev.reverse();
+            assert_eq!(rev, all);
+            assert_eq!(bptree.verify()?, vec![]);
+
+            // The loaded tree keeps working as an ordinary one.
+            for i in 0..n {
+                assert_eq!(bptree.get((i * 3).into())?, Some(i.into()));
+                bptree.insert((i * 3 + 1).into(), i.into())?;
+            }
+            for i in 0..n {
+                assert_eq!(bptree.remove((i * 3).into())?, Some(i.into()));
+            }
+            assert_eq!(bptree.range(..).count() as u64, n);
+        }
+
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        assert!(matches!(
+            bptree.bulk_load([(1, 1), (1, 2)].map(|(k, v)| (k.into(), v.into())), 1.0),
+            Err(BPTreeError::UnsortedInput { index: 1 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_bulk_load_keeps_tree() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        bptree.insert(1.into(), 1.into())?;
+        bptree.remove(1.into())?;
+        let root_block = bptree.root_block();
+        let free = allocator.borrow().free_blocks_count();
+
+        // Enough sorted entries to write a few leaves before the duplicate is found.
+        let m = bptree.get_m();
+        let entries = (0..3 * m).chain([3 * m - 1]).map(|i| (i.into(), i.into()));
+        assert!(matches!(
+            bptree.bulk_load(entries, 1.0),
+            Err(BPTreeError::UnsortedInput { index }) if index == 3 * m
+        ));
+        assert_eq!(allocator.borrow().free_blocks_count(), free);
+
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert_eq!(bptree.root_block(), root_block);
+        assert_eq!(bptree.verify()?, vec![]);
+        bptree.bulk_load((0..3 * m).map(|i| (i.into(), i.into())), 1.0)?;
+        assert_eq!(bptree.len(), 3 * m);
+        assert_eq!(bptree.verify()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_from_descriptor() -> Result<(), BPTreeError> {
+        let disk = Rc::new(RefCell::new(MemDisk::new(4096 * 4096)));
+        let n = 10_000;
+        let (desc_block, height) = {
+            let iocontext: TestIOContext =
+                Rc::new(RefCell::new(IOContext::new(1024, disk.clone())));
+            let allocator: Rc<RefCell<TestBPTreeAllocator>> =
+                Rc::new(RefCell::new(BPTreeAllocator::create(iocontext.clone(), 0)?));
+            let desc_block = allocator.borrow_mut().alloc()?;
+            let mut bptree: BPTree<_, _, _> =
+                BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+            for i in 0..n {
+                bptree.insert(i.into(), (i * 2).into())?;
+            }
+            assert!(bptree.height() > 1);
+            iocontext.borrow_mut().flush()?;
+            (desc_block, bptree.height())
+        };
+
+        let iocontext: TestIOContext = Rc::new(RefCell::new(IOContext::new(1024, disk)));
+        let allocator: Rc<RefCell<TestBPTreeAllocator>> =
+            Rc::new(RefCell::new(BPTreeAllocator::open(iocontext.clone(), 0)?));
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert_eq!(bptree.len(), n);
+        assert_eq!(bptree.height(), height);
+        assert_eq!(bptree.verify()?, vec![]);
+        for i in 0..n {
+            assert_eq!(bptree.get(i.into())?, Some((i * 2).into()));
+        }
+        assert_eq!(bptree.first()?, Some((0.into(), 0.into())));
+        assert_eq!(bptree.last()?, Some(((n - 1).into(), ((n - 1) * 2).into())));
+        assert_eq!(bptree.remove(0.into())?, Some(0.into()));
+        assert_eq!(bptree.len(), n - 1);
+
+        // The free-space tree's root block is not a descriptor.
+        assert!(matches!(
+            BPTree::<_, _, _>::open(1, iocontext, allocator),
+            Err(BPTreeError::BadDescriptor { block: 1 })
+        ));
+        Ok(())
+    }
+
+    #[repr(C)]
+    #[derive(
+        FromBytes,
+        IntoBytes,
+        Unaligned,
+        Immutable,
+        KnownLayout,
+        Clone,
+        Copy,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        Debug,
+    )]
+    struct ExtentKey {
+        inode: U64,
+        offset: U64,
+    }
+
+    #[repr(C)]
+    #[derive(
+        FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Clone, Copy, PartialEq, Debug,
+    )]
+    struct ExtentRecord {
+        start: U64,
+        len: U64,
+        flags: U64,
+    }
+
+    #[test]
+    fn composite_key() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(1024)?;
+        let mut bptree: BPTree<_, _, _, ExtentKey, ExtentRecord> =
+            BPTree::new(iocontext.clone(), allocator.clone());
+        assert_eq!(
+            bptree.get_m(),
+            (4096 - size_of::<NodeHeader>() as u64) / (16 + 24)
+        );
+
+        let key = |inode: u64, offset: u64| ExtentKey {
+            inode: inode.into(),
+            offset: offset.into(),
+        };
+        let record = |inode: u64, offset: u64| ExtentRecord {
+            start: (inode * 1000 + offset).into(),
+            len: offset.into(),
+            flags: inode.into(),
+        };
+
+        let n = 8 * bptree.get_m();
+        for i in 0..n {
+            let (inode, offset) = (pseudo_random_mapper(i) % 16, i);
+            bptree.insert(key(inode, offset), record(inode, offset))?;
+        }
+        for i in 0..n {
+            let inode = pseudo_random_mapper(i) % 16;
+            assert_eq!(bptree.get(key(inode, i))?, Some(record(inode, i)));
+        }
+
+        // Keys order by inode first, so one inode's extents form a contiguous range.
+        let extents = bptree
+            .range(key(3, 0)..key(4, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let expected = (0..n)
+            .filter(|&i| pseudo_random_mapper(i) % 16 == 3)
+            .map(|i| (key(3, i), record(3, i)))
+            .collect::<Vec<_>>();
+        assert_eq!(extents, expected);
+
+        for (k, v) in expected {
+            assert_eq!(bptree.remove(k)?, Some(v));
+        }
+        assert_eq!(bptree.range(key(3, 0)..key(4, 0)).count(), 0);
+        assert_eq!(bptree.len(), n - extents.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        let empty = bptree.stats()?;
+        assert_eq!((empty.height, empty.levels.len()), (0, 0));
+        assert_eq!(empty.blocks_used, 1);
+
+        // Full leaves, and internal nodes holding the last few children.
+        let m = bptree.get_m();
+        let n = 3 * m * (m - 1);
+        bptree.bulk_load((0..n).map(|i| (U64::new(i), U64::new(i))), 1.0)?;
+        let stats = bptree.stats()?;
+        assert_eq!(stats.height, 3);
+        assert_eq!(stats.num_keys, n);
+        assert_eq!(stats.leaf_nodes, 3 * m);
+        assert_eq!(stats.internal_nodes, 4);
+        assert_eq!(stats.blocks_used, 3 * m + 4 + 1);
+        assert_eq!(
+            stats.levels.iter().map(|l| l.nodes).collect::<Vec<_>>(),
+            vec![1, 3, 3 * m]
+        );
+        let leaves = &stats.levels[2];
+        assert_eq!(
+            (leaves.keys, leaves.avg_fill, leaves.min_fill),
+            (n, 1.0, 1.0)
+        );
+
+        for i in (0..n).step_by(3) {
+            bptree.remove(i.into())?;
+        }
+        let stats = bptree.stats()?;
+        assert_eq!(stats.num_keys, bptree.len());
+        let leaves = stats.levels.last().unwrap();
+        assert!(leaves.min_fill <= leaves.avg_fill && leaves.avg_fill < 1.0);
+        assert!(leaves.min_fill >= bptree.min_keys() as f64 / (m - 1) as f64);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_on_write_snapshots() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        bptree.set_copy_on_write()?;
+        let n = 8 * bptree.get_m();
+        for i in 0..n {
+            bptree.insert(pseudo_random_mapper(i).into(), i.into())?;
+        }
+        let before = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+
+        let mut snapshot = bptree.snapshot()?;
+        assert!(matches!(
+            snapshot.insert(1.into(), 1.into()),
+            Err(BPTreeError::ReadOnly)
+        ));
+        for i in (0..n).step_by(2) {
+            bptree.remove(pseudo_random_mapper(i).into())?;
+        }
+        for i in n..2 * n {
+            bptree.insert(pseudo_random_mapper(i).into(), i.into())?;
+        }
+        let second = bptree.snapshot()?;
+        for i in n..2 * n {
+            bptree.insert(pseudo_random_mapper(i).into(), (i + 1).into())?;
+        }
+
+        // The snapshots still see the tree as it was, in both directions.
+        assert_eq!(snapshot.range(..).collect::<Result<Vec<_>, _>>()?, before);
+        let mut rev = snapshot.range(..).rev().collect::<Result<Vec<_>, _>>()?;
+        rev.reverse();
+        assert_eq!(rev, before);
+        assert_eq!(snapshot.len(), n);
+        assert_eq!(second.get(pseudo_random_mapper(n).into())?, Some(n.into()));
+        assert_eq!(
+            bptree.get(pseudo_random_mapper(n).into())?,
+            Some((n + 1).into())
+        );
+        assert_eq!(bptree.get(pseudo_random_mapper(0).into())?, None);
+        assert_eq!(
+            snapshot.get(pseudo_random_mapper(0).into())?,
+            Some(0.into())
+        );
+
+        let live = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+        let mut rev = bptree.range(..).rev().collect::<Result<Vec<_>, _>>()?;
+        rev.reverse();
+        assert_eq!(live.len() as u64, n / 2 + n);
+        assert_eq!(rev, live);
+        for tree in [&bptree, &snapshot, &second] {
+            assert_eq!(tree.verify()?, vec![]);
+        }
+
+        let reopened: BPTree<_, _, _> =
+            BPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert!(reopened.is_copy_on_write());
+        let kept = reopened.snapshots();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].range(..).collect::<Result<Vec<_>, _>>()?, before);
+        assert_eq!(kept[1].get(pseudo_random_mapper(n).into())?, Some(n.into()));
+
+        // Once the snapshots are gone, emptying the tree hands every node back.
+        bptree.release_snapshot(snapshot)?;
+        bptree.release_snapshot(second)?;
+        for i in 0..2 * n {
+            bptree.remove(pseudo_random_mapper(i).into())?;
+        }
+        assert!(bptree.is_empty());
+        bptree.commit()?;
+        let mut free_blocks = 0;
+        while allocator.borrow_mut().alloc().is_ok() {
+            free_blocks += 1;
+        }
+        assert_eq!(free_blocks, 4096 - 4);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_on_write_commit() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        bptree.set_copy_on_write()?;
+        let generation = bptree.check().generation;
+        let n = 4 * bptree.get_m();
+        for i in 0..n {
+            bptree.insert(pseudo_random_mapper(i).into(), i.into())?;
+        }
+
+        // Nothing reaches the descriptor, or moves on to the next generation, before the commit.
+        assert_eq!(bptree.check().generation, generation);
+        let reopened: BPTree<_, _, _> =
+            BPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert!(reopened.is_empty());
+        bptree.commit()?;
+        assert_eq!(bptree.check().generation, generation + 1);
+        let reopened: BPTree<_, _, _> =
+            BPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert_eq!(reopened.len(), n);
+        assert_eq!(reopened.verify()?, vec![]);
+
+        // Removing a key that is not there changes nothing, so copies nothing either. The first
+        // change after a commit copies its path; the next one changes the copies.
+        let free = allocator.borrow().free_blocks_count();
+        assert_eq!(bptree.remove(pseudo_random_mapper(n).into())?, None);
+        assert_eq!(allocator.borrow().free_blocks_count(), free);
+        bptree.update(pseudo_random_mapper(0).into(), |_| Some(1.into()))?;
+        let copied = free - allocator.borrow().free_blocks_count();
+        assert_eq!(copied, bptree.height());
+        bptree.update(pseudo_random_mapper(0).into(), |_| Some(2.into()))?;
+        assert_eq!(allocator.borrow().free_blocks_count(), free - copied);
+        bptree.commit()?;
+        assert_eq!(allocator.borrow().free_blocks_count(), free);
+        assert_eq!(bptree.verify()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_on_write_failed_insert_keeps_descriptor() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(64)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        bptree.set_copy_on_write()?;
+
+        let mut n = 0;
+        let err = loop {
+            match bptree
+                .insert(pseudo_random_mapper(n).into(), n.into())
+                .and_then(|()| bptree.commit())
+            {
+                Ok(()) => n += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(
+            err,
+            BPTreeError::AllocateError(BlockAllocateError::NoFreeBlocks)
+        ));
+
+        // The insert was cut short after copying part of its path, but none of that is visible
+        // through the descriptor, which still names the tree as of the last commit.
+        let reopened: BPTree<_, _, _> =
+            BPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert_eq!(reopened.len(), n);
+        assert_eq!(reopened.verify()?, vec![]);
+        assert_eq!(reopened.get(pseudo_random_mapper(n).into())?, None);
+        for i in 0..n {
+            assert_eq!(
+                reopened.get(pseudo_random_mapper(i).into())?,
+                Some(i.into())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn update() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+
+        // Insert-if-absent only writes the first time.
+        assert_eq!(bptree.update(7.into(), |old| old.or(Some(1.into())))?, None);
+        assert_eq!(
+            bptree.update(7.into(), |old| old.or(Some(2.into())))?,
+            Some(1.into())
+        );
+        assert_eq!(bptree.get(7.into())?, Some(1.into()));
+
+        // Compare-and-swap.
+        let cas = |expected: u64, new: u64| {
+            move |old: Option<U64>| match old {
+                Some(val) if val.get() == expected => Some(new.into()),
+                _ => old,
+            }
+        };
+        assert_eq!(bptree.update(7.into(), cas(5, 6))?, Some(1.into()));
+        assert_eq!(bptree.get(7.into())?, Some(1.into()));
+        bptree.update(7.into(), cas(1, 6))?;
+        assert_eq!(bptree.get(7.into())?, Some(6.into()));
+
+        assert_eq!(bptree.update(7.into(), |_| None)?, Some(6.into()));
+        assert_eq!(bptree.update(7.into(), |_| None)?, None);
+        assert!(bptree.is_empty());
+
+        // A mix of all of the above keeps the tree balanced and in line with a map.
+        let mut model = std::collections::BTreeMap::new();
+        let n = 32 * bptree.get_m();
+        for i in 0..4 * n {
+            let key = pseudo_random_mapper(i) % n;
+            let action = pseudo_random_mapper(key ^ i) % 3;
+            let f = |old: Option<U64>| match action {
+                0 => None,
+                1 => Some(U64::new(old.map_or(0, |val| val.get()) + 1)),
+                _ => old.or(Some(U64::new(i))),
+            };
+            let expected = model.get(&key).copied();
+            let old = bptree.update(key.into(), f)?;
+            assert_eq!(old.map(|val| val.get()), expected);
+            match f(expected.map(U64::new)) {
+                Some(val) => model.insert(key, val.get()),
+                None => model.remove(&key),
+            };
+        }
+        assert_eq!(bptree.len(), model.len() as u64);
+        let all = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            all,
+            model
+                .iter()
+                .map(|(&k, &v)| (k.into(), v.into()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(bptree.verify()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn apply_batch() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        let mut model = std::collections::BTreeMap::new();
+        let m = bptree.get_m();
+
+        let mut check = |bptree: &mut BPTree<_, _, _>, ops: Vec<BatchOp<U64, U64>>| {
+            for op in &ops {
+                match *op {
+                    BatchOp::Put(key, val) => model.insert(key.get(), val.get()),
+                    BatchOp::Delete(key) => model.remove(&key.get()),
+                };
+            }
+            bptree.apply_batch(ops)?;
+            let all = bptree.range(..).collect::<Result<Vec<_>, BPTreeError>>()?;
+            assert_eq!(
+                all,
+                model
+                    .iter()
+                    .map(|(&k, &v)| (k.into(), v.into()))
+                    .collect::<Vec<_>>()
+            );
+            let mut rev = bptree.range(..).rev().collect::<Result<Vec<_>, _>>()?;
+            rev.reverse();
+            assert_eq!(rev, all);
+            assert_eq!(bptree.len(), model.len() as u64);
+            assert_eq!(bptree.verify()?, vec![]);
+            Ok::<_, BPTreeError>(())
+        };
+        let put = |k: u64, v: u64| BatchOp::Put(k.into(), v.into());
+        let delete = |k: u64| BatchOp::Delete(k.into());
+
+        // Into an empty tree, deep enough to grow several levels at once.
+        check(&mut bptree, (0..m * m).map(|i| put(i * 4, i)).collect())?;
+        assert_eq!(bptree.height(), 3);
+        // Sparse puts and deletes across every leaf.
+        check(
+            &mut bptree,
+            (0..m * m * 4)
+                .filter_map(|k| match pseudo_random_mapper(k) % 16 {
+                    0 => Some(put(k, k + 1)),
+                    1 => Some(delete(k)),
+                    _ => None,
+                })
+                .collect(),
+        )?;
+        // A dense insert into one leaf, then deleting whole stretches of the tree.
+        check(
+            &mut bptree,
+            (0..4 * m).map(|i| put(2 * m + 1 + 4 * i, 0)).collect(),
+        )?;
+        check(&mut bptree, (m..m * m * 3).map(delete).collect())?;
+        check(&mut bptree, (0..m * m * 4).map(delete).collect())?;
+        assert_eq!(bptree.height(), 1);
+        check(&mut bptree, vec![])?;
+
+        assert!(matches!(
+            bptree.apply_batch([put(1, 1), put(3, 3), delete(3)]),
+            Err(BPTreeError::UnsortedInput { index: 2 })
+        ));
+
+        // Batches copy what they touch in copy-on-write trees.
+        let mut cow: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        cow.set_copy_on_write()?;
+        cow.apply_batch((0..m * 8).map(|i| put(i, i)))?;
+        let snapshot = cow.snapshot()?;
+        cow.apply_batch((0..m * 8).step_by(3).map(delete))?;
+        assert_eq!(snapshot.range(..).count() as u64, m * 8);
+        assert_eq!(cow.len(), m * 8 - (m * 8).div_ceil(3));
+        assert_eq!(snapshot.verify()?, vec![]);
+        assert_eq!(cow.verify()?, vec![]);
+        cow.release_snapshot(snapshot)?;
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batch_keeps_tree() -> Result<(), BPTreeError> {
+        for cow in [false, true] {
+            let (iocontext, allocator) = setup(64)?;
+            let desc_block = allocator.borrow_mut().alloc()?;
+            let mut bptree: BPTree<_, _, _> =
+                BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+            if cow {
+                bptree.set_copy_on_write()?;
+            }
+            let m = bptree.get_m();
+            let put = |k: u64| BatchOp::Put(k.into(), k.into());
+            bptree.apply_batch((0..4 * m).map(|i| put(2 * i)))?;
+            bptree.commit()?;
+            let free = allocator.borrow().free_blocks_count();
+
+            // Far more leaves than the disk has blocks for.
+            assert!(matches!(
+                bptree.apply_batch((0..64 * m).map(|i| put(8 * i + 1))),
+                Err(BPTreeError::AllocateError(BlockAllocateError::NoFreeBlocks))
+            ));
+            assert_eq!(allocator.borrow().free_blocks_count(), free);
+            assert_eq!(bptree.len(), 4 * m);
+            assert_eq!(bptree.verify()?, vec![]);
+            let keys = bptree
+                .range(..)
+                .map(|entry| entry.map(|(key, _)| key.get()))
+                .collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(keys, (0..4 * m).map(|i| 2 * i).collect::<Vec<_>>());
+
+            bptree.apply_batch((0..m).map(|i| put(8 * i + 1)))?;
+            assert_eq!(bptree.len(), 5 * m);
+            assert_eq!(bptree.verify()?, vec![]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn order_statistics() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(4096)?;
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        let key = U64::new;
+        assert_eq!((bptree.count(..)?, bptree.rank(key(5))?), (0, 0));
+        assert_eq!(bptree.select(0)?, None);
+
+        let n = 16 * bptree.get_m();
+        for i in 0..n {
+            bptree.insert(key(pseudo_random_mapper(i) % (4 * n)), key(i))?;
+        }
+        for i in (0..n).step_by(3) {
+            bptree.remove(key(pseudo_random_mapper(i) % (4 * n)))?;
+        }
+        for i in 0..n / 4 {
+            // Toggles the key: removes it where present and adds it where not.
+            bptree.update(key(i * 7), |val| match val {
+                Some(_) => None,
+                None => Some(key(i)),
+            })?;
+        }
+        assert_eq!(bptree.verify()?, vec![]);
+
+        let model = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(bptree.count(..)?, model.len() as u64);
+        for (i, &(k, v)) in model.iter().enumerate().step_by(7) {
+            assert_eq!(bptree.rank(k)?, i as u64);
+            assert_eq!(bptree.rank(k + 1)?, i as u64 + 1);
+            assert_eq!(bptree.select(i as u64)?, Some((k, v)));
+        }
+        assert_eq!(bptree.select(model.len() as u64)?, None);
+
+        let (lo, hi) = (key(n / 2), key(3 * n));
+        let expected = model.iter().filter(|(k, _)| (lo..hi).contains(k)).count() as u64;
+        assert_eq!(bptree.count(lo..hi)?, expected);
+        assert_eq!(
+            bptree.count(lo..=hi)?,
+            expected + bptree.get(hi)?.is_some() as u64
+        );
+        assert_eq!(
+            bptree.count((std::ops::Bound::Excluded(lo), std::ops::Bound::Unbounded))?,
+            bptree.count(lo..)? - bptree.get(lo)?.is_some() as u64
+        );
+        assert_eq!(bptree.count(hi..lo)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn node_header_checks() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(1024)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree: BPTree<_, _, _> =
+            BPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        for i in 0..4 * bptree.get_m() {
+            bptree.insert(i.into(), i.into())?;
+        }
+        let leaf = bptree.first_leaf();
+        let read = |block_idx: u64, check: NodeCheck| -> Result<(), BPTreeError> {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            NodeView::<U64, U64>::get_from_bytes(&block, bptree.get_m(), check)?;
+            Ok(())
+        };
+        let flip = |offset: usize| -> Result<(), BPTreeError> {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get_mut(leaf)?;
+            block.get()[offset] ^= 1;
+            Ok(())
+        };
+
+        read(leaf, bptree.check())?;
+        assert!(matches!(
+            read(desc_block, bptree.check()),
+            Err(BPTreeError::NodeParseError(NodeParseError::BadMagic(_)))
+        ));
+        let other_tree = NodeCheck {
+            tree_id: desc_block + 1,
+            ..bptree.check()
+        };
+        assert!(matches!(
+            read(leaf, other_tree),
+            Err(BPTreeError::NodeParseError(NodeParseError::WrongTree { found, .. }))
+                if found == desc_block
+        ));
+        // The first leaf was written by the first insert, right after `create`.
+        let older = NodeCheck {
+            generation: 0,
+            ..bptree.check()
+        };
+        assert!(matches!(
+            read(leaf, older),
+            Err(BPTreeError::NodeParseError(
+                NodeParseError::FutureGeneration {
+                    found: 1,
+                    current: 0
+                }
+            ))
+        ));
+
+        flip(size_of::<NodeHeader>())?;
+        assert!(matches!(
+            bptree.get(1.into()),
+            Err(BPTreeError::NodeParseError(
+                NodeParseError::BadChecksum { .. }
+            ))
+        ));
+        flip(size_of::<NodeHeader>())?;
+        // Slots past the ones in use are never read, so the checksum leaves them out.
+        let last_val = size_of::<NodeHeader>() + (2 * bptree.get_m() as usize - 1) * 8;
+        flip(last_val)?;
+        assert_eq!(bptree.get(1.into())?, Some(1.into()));
+        flip(last_val)?;
+        {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(leaf)?;
+            let block = block.get();
+            assert!(matches!(
+                NodeView::<U64, Child>::get_from_bytes(&block, bptree.get_m(), bptree.check()),
+                Err(NodeParseError::BadLayout {
+                    key_size: 8,
+                    val_size: 8,
+                    ..
+                })
+            ));
+        }
+        flip(std::mem::offset_of!(NodeHeader, is_leaf))?;
+        assert!(matches!(
+            bptree.get(1.into()),
+            Err(BPTreeError::NodeParseError(NodeParseError::BadLevel {
+                level: 0,
+                is_leaf: 0
+            }))
+        ));
+        flip(std::mem::offset_of!(NodeHeader, is_leaf))?;
+
+        // Order statistics trust the key count only once the checksum has been checked.
+        flip(std::mem::offset_of!(NodeHeader, num_keys))?;
+        for result in [
+            bptree.select(0).map(|_| ()),
+            bptree.rank(1.into()).map(|_| ()),
+        ] {
+            assert!(matches!(
+                result,
+                Err(BPTreeError::NodeParseError(
+                    NodeParseError::BadChecksum { .. }
+                ))
+            ));
+        }
+        flip(std::mem::offset_of!(NodeHeader, num_keys))?;
+
+        let reopened: BPTree<_, _, _> = BPTree::open(desc_block, iocontext.clone(), allocator)?;
+        assert_eq!(reopened.check().generation, bptree.check().generation);
+        assert_eq!(reopened.get(1.into())?, Some(1.into()));
+        assert_eq!(reopened.verify()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_reports_corruption() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(1024)?;
+        let mut bptree: BPTree<_, _, _> = BPTree::new(iocontext.clone(), allocator.clone());
+        assert_eq!(bptree.verify()?, vec![]);
+        let n = 4 * bptree.get_m();
+        for i in 0..n {
+            bptree.insert(i.into(), i.into())?;
         }
+        assert_eq!(bptree.height(), 2);
+        assert_eq!(bptree.verify()?, vec![]);
+
+        let root = bptree.root_block().unwrap();
+        let leaf = bptree.first_leaf();
+        let edit = |block_idx: u64, f: &dyn Fn(NodeViewMut<U64, U64>)| -> Result<(), BPTreeError> {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            f(NodeViewMut::get_from_bytes(
+                &mut block,
+                bptree.get_m(),
+                bptree.check(),
+            )?);
+            Ok(())
+        };
+        let edit_root = |f: &dyn Fn(NodeViewMut<U64, Child>)| -> Result<(), BPTreeError> {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get_mut(root)?;
+            let mut block = block.get();
+            f(NodeViewMut::get_from_bytes(
+                &mut block,
+                bptree.get_m(),
+                bptree.check(),
+            )?);
+            Ok(())
+        };
+
+        edit(leaf, &|node| node.keys.swap(0, 1))?;
+        assert_eq!(
+            bptree.verify()?,
+            vec![Violation::UnsortedKeys {
+                block: leaf,
+                idx: 1
+            }]
+        );
+        edit(leaf, &|node| node.keys.swap(0, 1))?;
+
+        // The first leaf holds the keys below the first separator; lowering the separator by
+        // one leaves its last key out of bounds.
+        let separator = {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(root)?;
+            let block = block.get();
+            NodeView::<U64, Child>::get_from_bytes(&block, bptree.get_m(), bptree.check())?.keys[0]
+                .get()
+        };
+        edit_root(&|node| node.keys[0] -= 1)?;
+        assert_eq!(
+            bptree.verify()?,
+            vec![Violation::OutOfBounds {
+                block: leaf,
+                idx: separator as usize - 1
+            }]
+        );
+        edit_root(&|node| node.keys[0] += 1)?;
+
+        edit_root(&|node| node.vals[0].count += 1)?;
+        assert_eq!(
+            bptree.verify()?,
+            vec![Violation::SubtreeCount {
+                block: root,
+                idx: 0,
+                expected: separator,
+                found: separator + 1
+            }]
+        );
+        edit_root(&|node| node.vals[0].count -= 1)?;
+
+        // A leaf that fails its checksum is reported, and the walk goes on past it.
+        let (second, second_count) = {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(root)?;
+            let block = block.get();
+            let child =
+                NodeView::<U64, Child>::get_from_bytes(&block, bptree.get_m(), bptree.check())?
+                    .vals[1];
+            (child.block.get(), child.count.get())
+        };
+        let flip = || -> Result<(), BPTreeError> {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get_mut(second)?;
+            block.get()[size_of::<NodeHeader>()] ^= 1;
+            Ok(())
+        };
+        flip()?;
+        let violations = bptree.verify()?;
+        assert!(matches!(
+            violations[0],
+            Violation::Unreadable {
+                block,
+                error: NodeParseError::BadChecksum { .. }
+            } if block == second
+        ));
+        assert!(violations.contains(&Violation::KeyCount {
+            expected: n - second_count,
+            found: n
+        }));
+        flip()?;
+        assert_eq!(bptree.verify()?, vec![]);
+
+        edit(leaf, &|node| node.header.next = U64::new(leaf))?;
+        let violations = bptree.verify()?;
+        assert!(matches!(
+            violations[..],
+            [Violation::NextLink { block, found, .. }] if block == leaf && found == leaf
+        ));
 
+        // Routing two children to one leaf also breaks the chain and the key count.
+        edit_root(&|node| node.vals[1] = node.vals[0])?;
+        let violations = bptree.verify()?;
+        assert!(violations.contains(&Violation::SharedBlock { block: leaf }));
+        assert!(
+            violations
+                .iter()
+                .any(|v| matches!(v, Violation::KeyCount { .. }))
+        );
         Ok(())
     }
 }
@@ -0,0 +1,559 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::utils::bp_tree::bp_tree_node::{Child, NodeHeader, NodeView, NodeViewMut};
+use crate::utils::bp_tree::{BPTree, BPTreeError, NodeItem};
+use crate::utils::cache::Cache;
+
+/// One change in a batch passed to `BPTree::apply_batch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOp<K, V> {
+    Put(K, V),
+    Delete(K),
+}
+
+impl<K: Copy, V> BatchOp<K, V> {
+    pub fn key(&self) -> K {
+        match self {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) => *key,
+        }
+    }
+}
+
+/// Nodes a node was rewritten into, in key order, with the separators between them.
+struct Pieces<K> {
+    blocks: Vec<Child>,
+    seps: Vec<K>,
+}
+
+/// What a batch does below one node, worked out before anything is changed.
+#[derive(Default)]
+struct Plan {
+    added: u64,
+    removed: u64,
+    /// Nodes it is rewritten into, before any are evened out.
+    pieces: u64,
+    /// Most blocks applying the batch may allocate below it.
+    blocks: u64,
+}
+
+impl<D, C, A, K, V> BPTree<D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    /// Applies puts and deletes sorted by strictly increasing key in one pass down the tree.
+    ///
+    /// The batch is split among the children of each node by its separators, so every node on
+    /// the way is read once. A node that overflows is split evenly into as many nodes as it
+    /// needs, and one that underflows is merged with or evened out against a neighbour, once
+    /// per node. Nothing is changed if the batch turns out not to be sorted, or if the blocks
+    /// it may need cannot all be allocated up front.
+    pub fn apply_batch(
+        &mut self,
+        ops: impl IntoIterator<Item = BatchOp<K, V>>,
+    ) -> Result<(), BPTreeError> {
+        if self.is_block_manager() {
+            return Err(BPTreeError::IllegalUse);
+        }
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        let ops = ops.into_iter().collect::<Vec<_>>();
+        if let Some(index) = ops.windows(2).position(|w| w[0].key() >= w[1].key()) {
+            return Err(BPTreeError::UnsortedInput {
+                index: index as u64 + 1,
+            });
+        }
+
+        let mut plan = match self.root_block {
+            Some(root_block) => {
+                let mut plan = self.plan_batch(root_block, &ops)?;
+                plan.blocks += self.cow as u64;
+                plan
+            }
+            None => {
+                if !ops.iter().any(|op| matches!(op, BatchOp::Put(..))) {
+                    return Ok(());
+                }
+                let mut plan = self.plan_leaf(&[], &ops);
+                plan.blocks += 1;
+                plan
+            }
+        };
+        // Every level that does not fit in one node gets a new one above it.
+        let mut pieces = plan.pieces;
+        while pieces > 1 {
+            pieces = pieces.div_ceil(self.m);
+            plan.blocks += pieces;
+        }
+
+        self.reserve(plan.blocks)?;
+        let applied = self.apply_planned(&ops);
+        for block_idx in std::mem::take(&mut self.reserved) {
+            self.free_node(block_idx)?;
+        }
+        applied?;
+        self.num_keys = self.num_keys + plan.added - plan.removed;
+        self.write_descriptor()
+    }
+
+    /// Sets aside `count` blocks for `alloc` to hand out, so a batch that runs out of space
+    /// fails before it changes anything.
+    fn reserve(&mut self, count: u64) -> Result<(), BPTreeError> {
+        let goal = self.root_block.unwrap_or(0);
+        let mut allocator = self.allocator.as_ref().unwrap().borrow_mut();
+        for _ in 0..count {
+            match allocator.alloc_near(goal) {
+                Ok(block_idx) => self.reserved.push(block_idx),
+                Err(e) => {
+                    for block_idx in self.reserved.drain(..) {
+                        allocator.free(block_idx)?;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        // Handed out from the back, in the order they were allocated.
+        self.reserved.reverse();
+        Ok(())
+    }
+
+    /// Works out how `ops`, all of which route to `block_idx`, change the number of keys, and
+    /// an upper bound on the blocks applying them allocates.
+    ///
+    /// Evening out only ever removes nodes, so the pieces each node splits into bound the new
+    /// nodes. Copy-on-write trees also copy every node the batch reaches, and at most the two
+    /// neighbours each of them is evened out against.
+    fn plan_batch(&self, block_idx: u64, ops: &[BatchOp<K, V>]) -> Result<Plan, BPTreeError> {
+        if self.is_leaf(block_idx)? {
+            return Ok(self.plan_leaf(&self.read_entries(block_idx)?, ops));
+        }
+
+        let (keys, children) = self.read_children(block_idx)?;
+        let mut plan = Plan::default();
+        let mut new_children = 0;
+        let mut rest = ops;
+        for (idx, &child) in children.iter().enumerate() {
+            let split = match keys.get(idx) {
+                Some(&sep) => rest.partition_point(|op| op.key() < sep),
+                None => rest.len(),
+            };
+            let (child_ops, tail) = rest.split_at(split);
+            rest = tail;
+            if child_ops.is_empty() {
+                new_children += 1;
+                continue;
+            }
+
+            let child_plan = self.plan_batch(child.block.get(), child_ops)?;
+            plan.added += child_plan.added;
+            plan.removed += child_plan.removed;
+            plan.blocks += child_plan.blocks + 3 * self.cow as u64;
+            new_children += child_plan.pieces;
+        }
+        plan.pieces = new_children.div_ceil(self.m);
+        plan.blocks += plan.pieces - 1;
+        Ok(plan)
+    }
+
+    fn plan_leaf(&self, entries: &[(K, V)], ops: &[BatchOp<K, V>]) -> Plan {
+        let mut plan = Plan::default();
+        for op in ops {
+            let existed = entries
+                .binary_search_by(|(key, _)| key.cmp(&op.key()))
+                .is_ok();
+            match op {
+                BatchOp::Put(..) if !existed => plan.added += 1,
+                BatchOp::Delete(_) if existed => plan.removed += 1,
+                _ => {}
+            }
+        }
+        let len = entries.len() as u64 + plan.added - plan.removed;
+        plan.pieces = len.div_ceil(self.m - 1).max(1);
+        plan.blocks = plan.pieces - 1;
+        plan
+    }
+
+    /// Applies a sorted batch that `reserve` set aside enough blocks for.
+    fn apply_planned(&mut self, ops: &[BatchOp<K, V>]) -> Result<(), BPTreeError> {
+        if self.root_block.is_none() {
+            let root_block = self.alloc()?;
+            {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get_mut(root_block)?;
+                let mut block = block.get();
+                NodeHeader::init(&mut block, self.new_header(0, 0))?;
+            }
+            self.root_block = Some(root_block);
+            self.first_leaf = root_block;
+            self.height = 1;
+        }
+        self.cow_root()?;
+
+        let mut pieces = self.apply_to_node(self.root_block.unwrap(), ops)?;
+        while pieces.blocks.len() > 1 {
+            pieces = self.write_internal_pieces(None, pieces.seps, pieces.blocks)?;
+            self.height += 1;
+        }
+        self.root_block = Some(pieces.blocks[0].block.get());
+        self.collapse_root()
+    }
+
+    /// Applies `ops`, all of which route to `block_idx`, and returns the nodes it ends up as.
+    /// `block_idx` must be changeable in place. The nodes returned may hold fewer than
+    /// `min_keys`, in which case the caller evens them out against a sibling.
+    fn apply_to_node(
+        &mut self,
+        block_idx: u64,
+        ops: &[BatchOp<K, V>],
+    ) -> Result<Pieces<K>, BPTreeError> {
+        if self.is_leaf(block_idx)? {
+            let old = self.read_entries(block_idx)?;
+            let mut entries = Vec::with_capacity(old.len() + ops.len());
+            let mut old = old.into_iter().peekable();
+            for op in ops {
+                while let Some(entry) = old.next_if(|&(key, _)| key < op.key()) {
+                    entries.push(entry);
+                }
+                old.next_if(|&(key, _)| key == op.key());
+                if let BatchOp::Put(key, val) = *op {
+                    entries.push((key, val));
+                }
+            }
+            entries.extend(old);
+            return self.write_leaf_pieces(block_idx, entries);
+        }
+
+        let (keys, children) = self.read_children(block_idx)?;
+        let mut new_keys = Vec::with_capacity(keys.len());
+        let mut new_children = Vec::with_capacity(children.len());
+        let mut touched = Vec::with_capacity(children.len());
+        let mut rest = ops;
+        for (idx, &child) in children.iter().enumerate() {
+            if idx > 0 {
+                new_keys.push(keys[idx - 1]);
+            }
+            let split = match keys.get(idx) {
+                Some(&sep) => rest.partition_point(|op| op.key() < sep),
+                None => rest.len(),
+            };
+            let (child_ops, tail) = rest.split_at(split);
+            rest = tail;
+            if child_ops.is_empty() {
+                new_children.push(child);
+                touched.push(false);
+                continue;
+            }
+
+            let child = self.cow_node(child.block.get())?;
+            let pieces = self.apply_to_node(child, child_ops)?;
+            for (i, &piece) in pieces.blocks.iter().enumerate() {
+                if i > 0 {
+                    new_keys.push(pieces.seps[i - 1]);
+                }
+                new_children.push(piece);
+                touched.push(true);
+            }
+        }
+
+        self.even_children(&mut new_keys, &mut new_children, touched)?;
+        self.write_internal_pieces(Some(block_idx), new_keys, new_children)
+    }
+
+    /// Evens out every child in `children` marked in `touched` that holds fewer than
+    /// `min_keys`, against its left sibling if it has one and its right one otherwise.
+    ///
+    /// Internal nodes rebuilt this way can pick up a sibling's underfull child, so their own
+    /// children are evened out in turn.
+    fn even_children(
+        &mut self,
+        keys: &mut Vec<K>,
+        children: &mut Vec<Child>,
+        mut touched: Vec<bool>,
+    ) -> Result<(), BPTreeError> {
+        let mut idx = 0;
+        while idx < children.len() && children.len() > 1 {
+            if !touched[idx] || self.num_keys_of(children[idx].block.get())? >= self.min_keys() {
+                idx += 1;
+                continue;
+            }
+            let left = idx.saturating_sub(1);
+            let left_block = self.cow_node(children[left].block.get())?;
+            let right_block = self.cow_node(children[left + 1].block.get())?;
+            match self.even_out(left_block, keys[left], right_block)? {
+                Some(sep) => {
+                    keys[left] = sep;
+                    self.even_grandchildren(left_block)?;
+                    self.even_grandchildren(right_block)?;
+                    children[left + 1] = Child::new(right_block, self.subtree_count(right_block)?);
+                    touched[left + 1] = true;
+                }
+                None => {
+                    keys.remove(left);
+                    children.remove(left + 1);
+                    touched.remove(left + 1);
+                    self.even_grandchildren(left_block)?;
+                }
+            }
+            children[left] = Child::new(left_block, self.subtree_count(left_block)?);
+            touched[left] = true;
+            idx = left;
+        }
+        Ok(())
+    }
+
+    /// Runs `even_children` over all children of `block_idx`, if it is an internal node.
+    fn even_grandchildren(&mut self, block_idx: u64) -> Result<(), BPTreeError> {
+        if self.is_leaf(block_idx)? {
+            return Ok(());
+        }
+        let (mut keys, mut children) = self.read_children(block_idx)?;
+        let touched = vec![true; children.len()];
+        self.even_children(&mut keys, &mut children, touched)?;
+        self.write_children(block_idx, &keys, &children)
+    }
+
+    fn is_leaf(&self, block_idx: u64) -> Result<bool, BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        Ok(NodeHeader::checked(&block, self.check())?.is_leaf == 1)
+    }
+
+    /// Rebalances two adjacent siblings split by `sep`. Merges them into `left` if they fit in
+    /// one node, freeing `right`, and otherwise splits their contents evenly, returning the new
+    /// separator. Both must be changeable in place.
+    fn even_out(&mut self, left: u64, sep: K, right: u64) -> Result<Option<K>, BPTreeError> {
+        let is_leaf = self.is_leaf(left)?;
+        let max_keys = (self.m - 1) as usize;
+
+        let new_sep = if is_leaf {
+            let mut entries = self.read_entries(left)?;
+            entries.extend(self.read_entries(right)?);
+            if entries.len() <= max_keys {
+                self.write_entries(left, &entries)?;
+                None
+            } else {
+                let (head, tail) = entries.split_at(entries.len() / 2);
+                self.write_entries(left, head)?;
+                self.write_entries(right, tail)?;
+                Some(tail[0].0)
+            }
+        } else {
+            let (mut keys, mut children) = self.read_children(left)?;
+            let (right_keys, right_children) = self.read_children(right)?;
+            keys.push(sep);
+            keys.extend(right_keys);
+            children.extend(right_children);
+            if keys.len() <= max_keys {
+                self.write_children(left, &keys, &children)?;
+                None
+            } else {
+                let mid = keys.len() / 2;
+                self.write_children(left, &keys[..mid], &children[..=mid])?;
+                self.write_children(right, &keys[mid + 1..], &children[mid + 1..])?;
+                Some(keys[mid])
+            }
+        };
+
+        if new_sep.is_none() {
+            let next = {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get(right)?;
+                let block = block.get();
+                NodeHeader::checked(&block, self.check())?.next.get()
+            };
+            if !self.cow {
+                let mut ioc = self.io_context.borrow_mut();
+                {
+                    let block = ioc.get_mut(left)?;
+                    let mut block = block.get();
+                    NodeHeader::from_bytes_mut(&mut block)?.next = next.into();
+                }
+                Self::link_prev(&mut ioc, next, left)?;
+            }
+            self.free_node(right)?;
+        }
+        Ok(new_sep)
+    }
+
+    /// Writes `entries` back to the leaf `block_idx`, spread evenly over as many leaves as
+    /// they need.
+    fn write_leaf_pieces(
+        &mut self,
+        block_idx: u64,
+        entries: Vec<(K, V)>,
+    ) -> Result<Pieces<K>, BPTreeError> {
+        let max_keys = (self.m - 1) as usize;
+        let count = entries.len().div_ceil(max_keys).max(1);
+        let mut blocks = vec![block_idx];
+        for _ in 1..count {
+            let prev = *blocks.last().unwrap();
+            blocks.push(self.new_node_after(prev)?);
+        }
+
+        let mut seps = Vec::with_capacity(count - 1);
+        let mut pieces = Vec::with_capacity(count);
+        let mut start = 0;
+        for (i, &block) in blocks.iter().enumerate() {
+            let end = entries.len() * (i + 1) / count;
+            if i > 0 {
+                seps.push(entries[start].0);
+            }
+            self.write_entries(block, &entries[start..end])?;
+            pieces.push(Child::new(block, (end - start) as u64));
+            start = end;
+        }
+        Ok(Pieces {
+            blocks: pieces,
+            seps,
+        })
+    }
+
+    /// Writes an internal node over `children`, split by `keys`, spread evenly over as many
+    /// nodes as it needs. The first one reuses `block_idx`, or a fresh block if there is none.
+    fn write_internal_pieces(
+        &mut self,
+        block_idx: Option<u64>,
+        keys: Vec<K>,
+        children: Vec<Child>,
+    ) -> Result<Pieces<K>, BPTreeError> {
+        let block_idx = match block_idx {
+            Some(block_idx) => block_idx,
+            None => {
+                let block_idx = self.alloc_node_near(children[0].block.get())?;
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get_mut(block_idx)?;
+                let mut block = block.get();
+                // Only ever a new root, one level above the old one.
+                NodeHeader::init(&mut block, self.new_header(self.height as u16, 0))?;
+                block_idx
+            }
+        };
+        let count = children.len().div_ceil(self.m as usize);
+        let mut blocks = vec![block_idx];
+        for _ in 1..count {
+            let prev = *blocks.last().unwrap();
+            blocks.push(self.new_node_after(prev)?);
+        }
+
+        // Each piece takes a run of children; the key between two runs moves up.
+        let mut seps = Vec::with_capacity(count - 1);
+        let mut pieces = Vec::with_capacity(count);
+        let mut start = 0;
+        for (i, &block) in blocks.iter().enumerate() {
+            let end = children.len() * (i + 1) / count;
+            if i > 0 {
+                seps.push(keys[start - 1]);
+            }
+            self.write_children(block, &keys[start..end - 1], &children[start..end])?;
+            pieces.push(Child::new(
+                block,
+                Self::count_children(&children[start..end]),
+            ));
+            start = end;
+        }
+        Ok(Pieces {
+            blocks: pieces,
+            seps,
+        })
+    }
+
+    /// Allocates an empty node and links it into the level of `prev`, right after it.
+    fn new_node_after(&mut self, prev: u64) -> Result<u64, BPTreeError> {
+        let block_idx = self.alloc_node_near(prev)?;
+        let mut ioc = self.io_context.borrow_mut();
+        let (next, level) = {
+            let block = ioc.get_mut(prev)?;
+            let mut block = block.get();
+            let mut header = NodeHeader::from_bytes_mut(&mut block)?;
+            let next = header.next.get();
+            if !self.cow {
+                header.next = block_idx.into();
+            }
+            (next, header.level.get())
+        };
+        {
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let mut header = NodeHeader::init(&mut block, self.new_header(level, 0))?;
+            if !self.cow {
+                header.next = next.into();
+                header.prev = prev.into();
+            }
+        }
+        if !self.cow {
+            Self::link_prev(&mut ioc, next, block_idx)?;
+        }
+        Ok(block_idx)
+    }
+
+    fn num_keys_of(&self, block_idx: u64) -> Result<u64, BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        Ok(NodeHeader::checked(&block, self.check())?.num_keys.get())
+    }
+
+    fn read_entries(&self, block_idx: u64) -> Result<Vec<(K, V)>, BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        let nodeview = NodeView::<K, V>::get_from_bytes(&block, self.m, self.check())?;
+        let num_keys = nodeview.header.num_keys.get() as usize;
+        Ok(nodeview.keys[..num_keys]
+            .iter()
+            .copied()
+            .zip(nodeview.vals[..num_keys].iter().copied())
+            .collect())
+    }
+
+    fn read_children(&self, block_idx: u64) -> Result<(Vec<K>, Vec<Child>), BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        let nodeview = NodeView::<K, Child>::get_from_bytes(&block, self.m, self.check())?;
+        let num_keys = nodeview.header.num_keys.get() as usize;
+        Ok((
+            nodeview.keys[..num_keys].to_vec(),
+            nodeview.vals[..=num_keys].to_vec(),
+        ))
+    }
+
+    /// Replaces the contents of a leaf, keeping its links.
+    fn write_entries(&self, block_idx: u64, entries: &[(K, V)]) -> Result<(), BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get_mut(block_idx)?;
+        let mut block = block.get();
+        let nodeview = NodeViewMut::<K, V>::get_from_bytes(&mut block, self.m, self.check())?;
+        for (i, &(key, val)) in entries.iter().enumerate() {
+            nodeview.keys[i] = key;
+            nodeview.vals[i] = val;
+        }
+        nodeview.header.num_keys = (entries.len() as u64).into();
+        Ok(())
+    }
+
+    /// Replaces the contents of an internal node, keeping its links.
+    fn write_children(
+        &self,
+        block_idx: u64,
+        keys: &[K],
+        children: &[Child],
+    ) -> Result<(), BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get_mut(block_idx)?;
+        let mut block = block.get();
+        let nodeview = NodeViewMut::<K, Child>::get_from_bytes(&mut block, self.m, self.check())?;
+        nodeview.keys[..keys.len()].copy_from_slice(keys);
+        nodeview.vals[..children.len()].copy_from_slice(children);
+        nodeview.header.num_keys = (keys.len() as u64).into();
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::ops::{Deref, DerefMut, Range};
+
 use thiserror::Error;
 use zerocopy::byteorder::little_endian::*;
 use zerocopy::{CastError, FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
@@ -5,14 +7,97 @@ use zerocopy::{CastError, FromBytes, Immutable, IntoBytes, KnownLayout, Unaligne
 #[repr(C)]
 #[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
 pub struct NodeHeader {
+    pub magic: U32,
+    pub version: u8,
     pub is_leaf: u8,
-    _padding: [u8; 7],
+    /// Distance from the leaves, which are at level 0.
+    pub level: U16,
+    /// Copy-on-write trees only: how many parents and snapshot roots point at the node.
+    pub refs: U32,
+    /// CRC32C of the header and the slots in use, taken with this field set to 0.
+    pub checksum: U32,
+    pub tree_id: U64,
+    /// Generation of the tree when the node was written out.
+    pub generation: U64,
     pub num_keys: U64,
     pub next: U64,
     pub prev: U64,
+    /// Size of a key, of a value slot and the number of slots of each, which tell the checksum
+    /// where the slots in use lie.
+    pub key_size: U16,
+    pub val_size: U16,
+    pub fanout: U32,
+}
+
+pub const NODE_MAGIC: u32 = u32::from_le_bytes(*b"BPND");
+pub const NODE_VERSION: u8 = 2;
+
+const CHECKSUM_OFFSET: usize = std::mem::offset_of!(NodeHeader, checksum);
+
+/// What a node must match to belong to the tree reading it.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeCheck {
+    pub tree_id: u64,
+    /// Nodes written by a later generation than this are stale leftovers or misdirected.
+    pub generation: u64,
+}
+
+/// Slot of an internal node: a child and the number of keys stored in its subtree.
+#[repr(C)]
+#[derive(
+    FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Clone, Copy, Debug, PartialEq, Eq,
+)]
+pub struct Child {
+    pub block: U64,
+    pub count: U64,
+}
+
+impl Child {
+    pub fn new(block: u64, count: u64) -> Self {
+        Self {
+            block: block.into(),
+            count: count.into(),
+        }
+    }
+}
+
+pub const TREE_MAGIC: u64 = 0x4250_5452_4545;
+
+/// Fixed-size record describing a tree that has to be found again after an unmount.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
+pub struct TreeDescriptor {
+    pub magic: U64,
+    pub root: U64,
+    pub first_leaf: U64,
+    pub height: U64,
+    pub num_keys: U64,
+    pub fanout: U64,
+    pub flags: U64,
+    pub generation: U64,
+    /// Number of `SnapshotRecord`s stored right after the descriptor.
+    pub snapshots: U64,
+    /// Free-space trees only: blocks in all their extents, so counting them takes no walk.
+    pub free_blocks: U64,
+}
+
+/// A snapshot of a copy-on-write tree, kept in its descriptor block so it survives an unmount.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Clone, Copy, Debug)]
+pub struct SnapshotRecord {
+    /// Generation the snapshot was taken in, which also names it.
+    pub generation: U64,
+    pub root: U64,
+    pub first_leaf: U64,
+    pub height: U64,
+    pub num_keys: U64,
 }
 
-#[derive(Error, Debug)]
+/// Descriptor flag: the tree writes changed nodes to fresh blocks instead of updating them in
+/// place.
+pub const TREE_COW: u64 = 1;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum NodeParseError {
     #[error("Header alignment error")]
     HeaderAligenment,
@@ -22,64 +107,374 @@ pub enum NodeParseError {
     KvsAligenment,
     #[error("Kvs size error")]
     KvsSize,
+    #[error("Block does not hold a node (magic {0:#x})")]
+    BadMagic(u32),
+    #[error("Node format version {0} is not supported")]
+    UnsupportedVersion(u8),
+    #[error("Node level {level} does not match its leaf flag {is_leaf}")]
+    BadLevel { level: u16, is_leaf: u8 },
+    #[error("Node belongs to tree {found}, not {expected}")]
+    WrongTree { expected: u64, found: u64 },
+    #[error("Node generation {found} is newer than the tree's {current}")]
+    FutureGeneration { found: u64, current: u64 },
+    #[error("Node checksum is {found:#x}, but its contents hash to {expected:#x}")]
+    BadChecksum { expected: u32, found: u32 },
+    #[error("Node has {fanout} slots of {key_size} and {val_size} bytes, which is not this tree's")]
+    BadLayout {
+        key_size: u16,
+        val_size: u16,
+        fanout: u32,
+    },
 }
 
 impl NodeHeader {
-    pub fn new(is_leaf: bool, num_keys: u64) -> Self {
+    pub fn new(level: u16, num_keys: u64, tree_id: u64, generation: u64) -> Self {
         Self {
-            is_leaf: if is_leaf { 1 } else { 0 },
-            _padding: [0; 7],
+            magic: NODE_MAGIC.into(),
+            version: NODE_VERSION,
+            is_leaf: if level == 0 { 1 } else { 0 },
+            level: level.into(),
+            refs: 1.into(),
+            checksum: 0.into(),
+            tree_id: tree_id.into(),
+            generation: generation.into(),
             num_keys: num_keys.into(),
             next: U64::MAX_VALUE,
             prev: U64::MAX_VALUE,
+            key_size: 0.into(),
+            val_size: 0.into(),
+            fanout: 0.into(),
+        }
+    }
+
+    /// Records the layout of a node with `m` keys of type `K` and `m` slots of type `X`.
+    pub fn with_layout<K, X>(mut self, m: u64) -> Self {
+        self.key_size = (size_of::<K>() as u16).into();
+        self.val_size = (size_of::<X>() as u16).into();
+        self.fanout = (m as u32).into();
+        self
+    }
+
+    fn check_layout<K, X>(&self, m: u64) -> Result<(), NodeParseError> {
+        if self.key_size.get() as usize != size_of::<K>()
+            || self.val_size.get() as usize != size_of::<X>()
+            || self.fanout.get() as u64 != m
+        {
+            return Err(NodeParseError::BadLayout {
+                key_size: self.key_size.get(),
+                val_size: self.val_size.get(),
+                fanout: self.fanout.get(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Number of keys and of value slots in use, at most as many as there are.
+    fn used_slots(&self) -> (usize, usize) {
+        let fanout = self.fanout.get() as u64;
+        let keys = self.num_keys.get().min(fanout);
+        let vals = (keys + u64::from(self.is_leaf == 0)).min(fanout);
+        (keys as usize, vals as usize)
+    }
+
+    /// Byte ranges of the keys and values in use, cut short where `len` bytes end.
+    fn used_spans(&self, len: usize) -> [Range<usize>; 2] {
+        let (keys, vals) = self.used_slots();
+        let key_size = self.key_size.get() as usize;
+        let val_size = self.val_size.get() as usize;
+        let keys_start = size_of::<NodeHeader>();
+        let vals_start = keys_start + self.fanout.get() as usize * key_size;
+        let span = |start: usize, bytes: usize| start.min(len)..(start + bytes).min(len);
+        [
+            span(keys_start, keys * key_size),
+            span(vals_start, vals * val_size),
+        ]
+    }
+
+    /// Reads just the header, e.g. to tell whether the rest is a leaf before parsing it. Only
+    /// the format is checked, not the checksum.
+    pub fn from_bytes(bytes: &[u8]) -> Result<&Self, NodeParseError> {
+        let header = Self::ref_from_prefix(bytes)
+            .map_err(|e| match e {
+                CastError::Alignment(_) => NodeParseError::HeaderAligenment,
+                CastError::Size(_) => NodeParseError::HeaderSize,
+            })?
+            .0;
+        header.check_format()?;
+        Ok(header)
+    }
+
+    /// Reads the header and checks it as `NodeView::get_from_bytes` does, so its fields can be
+    /// trusted, e.g. to tell how to parse the rest.
+    pub fn checked(bytes: &[u8], check: NodeCheck) -> Result<&Self, NodeParseError> {
+        let header = Self::from_bytes(bytes)?;
+        header.check(bytes, check)?;
+        Ok(header)
+    }
+
+    /// Borrows the header of an existing node for writing. The checksum is checked first, as
+    /// the guard reseals the block when dropped and would otherwise bless a corrupted one.
+    pub fn from_bytes_mut(bytes: &mut [u8]) -> Result<HeaderMut<'_>, NodeParseError> {
+        Self::from_bytes(bytes)?.check_checksum(bytes)?;
+        Ok(HeaderMut { bytes })
+    }
+
+    /// Writes `header` over whatever `bytes` held, for a block that is becoming a node.
+    pub fn init(bytes: &mut [u8], header: NodeHeader) -> Result<HeaderMut<'_>, NodeParseError> {
+        header
+            .write_to_prefix(bytes)
+            .map_err(|_| NodeParseError::HeaderSize)?;
+        Ok(HeaderMut { bytes })
+    }
+
+    /// Whether more than one parent or snapshot points at the node.
+    pub fn is_shared(&self) -> bool {
+        self.refs.get() > 1
+    }
+
+    pub fn add_ref(&mut self) {
+        self.refs = (self.refs.get().max(1) + 1).into();
+    }
+
+    /// Drops one reference, returning how many are left.
+    pub fn drop_ref(&mut self) -> u32 {
+        self.refs = self.refs.get().saturating_sub(1).into();
+        self.refs.get()
+    }
+
+    fn check_format(&self) -> Result<(), NodeParseError> {
+        if self.magic.get() != NODE_MAGIC {
+            return Err(NodeParseError::BadMagic(self.magic.get()));
+        }
+        if self.version != NODE_VERSION {
+            return Err(NodeParseError::UnsupportedVersion(self.version));
+        }
+        if self.is_leaf > 1 || (self.level.get() == 0) != (self.is_leaf == 1) {
+            return Err(NodeParseError::BadLevel {
+                level: self.level.get(),
+                is_leaf: self.is_leaf,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_checksum(&self, bytes: &[u8]) -> Result<(), NodeParseError> {
+        let expected = checksum(bytes);
+        if self.checksum.get() != expected {
+            return Err(NodeParseError::BadChecksum {
+                expected,
+                found: self.checksum.get(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks everything the header records against the block it heads and the tree reading it.
+    fn check(&self, bytes: &[u8], check: NodeCheck) -> Result<(), NodeParseError> {
+        self.check_format()?;
+        if self.tree_id.get() != check.tree_id {
+            return Err(NodeParseError::WrongTree {
+                expected: check.tree_id,
+                found: self.tree_id.get(),
+            });
+        }
+        if self.generation.get() > check.generation {
+            return Err(NodeParseError::FutureGeneration {
+                found: self.generation.get(),
+                current: check.generation,
+            });
         }
+        self.check_checksum(bytes)
+    }
+}
+
+/// CRC32C of a node's header, with the checksum field read as 0, and of the slots its header
+/// says are in use. Slots past those are never read, so whatever they hold is left out.
+/// `bytes` must start with a header.
+fn checksum(bytes: &[u8]) -> u32 {
+    let header = NodeHeader::ref_from_prefix(bytes).unwrap().0;
+    let head = &bytes[..size_of::<NodeHeader>()];
+    let crc = crc32c::crc32c(&head[..CHECKSUM_OFFSET]);
+    let crc = crc32c::crc32c_append(crc, &[0; 4]);
+    let crc = crc32c::crc32c_append(crc, &head[CHECKSUM_OFFSET + 4..]);
+    header
+        .used_spans(bytes.len())
+        .into_iter()
+        .fold(crc, |crc, span| crc32c::crc32c_append(crc, &bytes[span]))
+}
+
+/// Header borrowed for writing; the block's checksum is brought up to date when it is dropped.
+pub struct HeaderMut<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl Deref for HeaderMut<'_> {
+    type Target = NodeHeader;
+
+    fn deref(&self) -> &NodeHeader {
+        // The size was checked when the guard was made.
+        NodeHeader::ref_from_prefix(self.bytes).unwrap().0
+    }
+}
+
+impl DerefMut for HeaderMut<'_> {
+    fn deref_mut(&mut self) -> &mut NodeHeader {
+        NodeHeader::mut_from_prefix(self.bytes).unwrap().0
+    }
+}
+
+impl Drop for HeaderMut<'_> {
+    fn drop(&mut self) {
+        let crc = checksum(self.bytes);
+        self.checksum = crc.into();
     }
 }
 
-pub struct NodeViewMut<'a> {
+/// View of a node with `m` keys of type `K` followed by `m` slots of type `X`: the values of a
+/// leaf, or the `Child` slots of an internal node. The block is resealed when it is dropped.
+pub struct NodeViewMut<'a, K, X>
+where
+    K: IntoBytes + Immutable,
+    X: IntoBytes + Immutable,
+{
     pub header: &'a mut NodeHeader,
-    pub keys: &'a mut [U64],
-    pub vals: &'a mut [U64],
+    pub keys: &'a mut [K],
+    pub vals: &'a mut [X],
 }
 
-impl<'a> NodeViewMut<'a> {
-    pub fn get_from_bytes(bytes: &'a mut [u8], m: u64) -> Result<Self, NodeParseError> {
+impl<'a, K, X> NodeViewMut<'a, K, X>
+where
+    K: FromBytes + IntoBytes + Immutable + KnownLayout,
+    X: FromBytes + IntoBytes + Immutable + KnownLayout,
+{
+    /// Parses an existing node, checked as by `NodeView::get_from_bytes`.
+    pub fn get_from_bytes(
+        bytes: &'a mut [u8],
+        m: u64,
+        check: NodeCheck,
+    ) -> Result<Self, NodeParseError> {
+        NodeHeader::checked(bytes, check)?.check_layout::<K, X>(m)?;
+        Self::split(bytes, m)
+    }
+
+    /// Writes `header` over whatever `bytes` held and returns a view of the new node. The
+    /// header must carry this view's layout.
+    pub fn init(bytes: &'a mut [u8], m: u64, header: NodeHeader) -> Result<Self, NodeParseError> {
+        header.check_layout::<K, X>(m)?;
+        header
+            .write_to_prefix(bytes)
+            .map_err(|_| NodeParseError::HeaderSize)?;
+        Self::split(bytes, m)
+    }
+
+    fn split(bytes: &'a mut [u8], m: u64) -> Result<Self, NodeParseError> {
         let (header, kvs) = NodeHeader::mut_from_prefix(bytes).map_err(|e| match e {
             CastError::Alignment(_) => NodeParseError::HeaderAligenment,
             CastError::Size(_) => NodeParseError::HeaderSize,
         })?;
-        let kvs = <[U64]>::mut_from_bytes(kvs).map_err(|e| match e {
-            CastError::Alignment(_) => NodeParseError::KvsAligenment,
-            CastError::Size(_) => NodeParseError::HeaderSize,
-        })?;
-        if kvs.len() < 2 * m as usize {
-            return Err(NodeParseError::KvsSize);
-        }
-        let (keys, vals) = kvs.split_at_mut(m as usize);
+        let (keys, vals) =
+            <[K]>::mut_from_prefix_with_elems(kvs, m as usize).map_err(|e| match e {
+                CastError::Alignment(_) => NodeParseError::KvsAligenment,
+                CastError::Size(_) => NodeParseError::KvsSize,
+            })?;
+        let (vals, _) =
+            <[X]>::mut_from_prefix_with_elems(vals, m as usize).map_err(|e| match e {
+                CastError::Alignment(_) => NodeParseError::KvsAligenment,
+                CastError::Size(_) => NodeParseError::KvsSize,
+            })?;
         Ok(NodeViewMut { header, keys, vals })
     }
 }
 
-pub struct NodeView<'a> {
+impl<K, X> Drop for NodeViewMut<'_, K, X>
+where
+    K: IntoBytes + Immutable,
+    X: IntoBytes + Immutable,
+{
+    fn drop(&mut self) {
+        self.header.checksum = 0.into();
+        let (keys, vals) = self.header.used_slots();
+        let crc = [
+            self.header.as_bytes(),
+            self.keys[..keys].as_bytes(),
+            self.vals[..vals].as_bytes(),
+        ]
+        .into_iter()
+        .fold(0, crc32c::crc32c_append);
+        self.header.checksum = crc.into();
+    }
+}
+
+pub struct NodeView<'a, K, X> {
     pub header: &'a NodeHeader,
-    pub keys: &'a [U64],
-    pub vals: &'a [U64],
+    pub keys: &'a [K],
+    pub vals: &'a [X],
 }
 
-impl<'a> NodeView<'a> {
-    pub fn get_from_bytes(bytes: &'a [u8], m: u64) -> Result<Self, NodeParseError> {
+impl<'a, K, X> NodeView<'a, K, X>
+where
+    K: FromBytes + Immutable + KnownLayout,
+    X: FromBytes + Immutable + KnownLayout,
+{
+    /// Parses a node, failing unless its magic, version, level, tree id, generation and
+    /// checksum all hold up.
+    pub fn get_from_bytes(
+        bytes: &'a [u8],
+        m: u64,
+        check: NodeCheck,
+    ) -> Result<Self, NodeParseError> {
+        NodeHeader::checked(bytes, check)?;
+        Self::split(bytes, m)
+    }
+
+    /// Splits a node whose header has already been checked, once its layout matches.
+    fn split(bytes: &'a [u8], m: u64) -> Result<Self, NodeParseError> {
         let (header, kvs) = NodeHeader::ref_from_prefix(bytes).map_err(|e| match e {
             CastError::Alignment(_) => NodeParseError::HeaderAligenment,
             CastError::Size(_) => NodeParseError::HeaderSize,
         })?;
-        let kvs = <[U64]>::ref_from_bytes(kvs).map_err(|e| match e {
-            CastError::Alignment(_) => NodeParseError::KvsAligenment,
-            CastError::Size(_) => NodeParseError::HeaderSize,
-        })?;
-        if kvs.len() < 2 * m as usize {
-            return Err(NodeParseError::KvsSize);
-        }
-        let (keys, vals) = kvs.split_at(m as usize);
+        header.check_layout::<K, X>(m)?;
+        let (keys, vals) =
+            <[K]>::ref_from_prefix_with_elems(kvs, m as usize).map_err(|e| match e {
+                CastError::Alignment(_) => NodeParseError::KvsAligenment,
+                CastError::Size(_) => NodeParseError::KvsSize,
+            })?;
+        let (vals, _) =
+            <[X]>::ref_from_prefix_with_elems(vals, m as usize).map_err(|e| match e {
+                CastError::Alignment(_) => NodeParseError::KvsAligenment,
+                CastError::Size(_) => NodeParseError::KvsSize,
+            })?;
         Ok(NodeView { header, keys, vals })
     }
 }
+
+/// A node parsed as a leaf or as an internal node, whichever its checked header says it is.
+pub enum Node<'a, K, V> {
+    Leaf(NodeView<'a, K, V>),
+    Internal(NodeView<'a, K, Child>),
+}
+
+impl<'a, K, V> Node<'a, K, V>
+where
+    K: FromBytes + Immutable + KnownLayout,
+    V: FromBytes + Immutable + KnownLayout,
+{
+    /// Parses a node checked as by `NodeView::get_from_bytes`, once for both kinds.
+    pub fn get_from_bytes(
+        bytes: &'a [u8],
+        m: u64,
+        check: NodeCheck,
+    ) -> Result<Self, NodeParseError> {
+        if NodeHeader::checked(bytes, check)?.is_leaf == 1 {
+            Ok(Node::Leaf(NodeView::split(bytes, m)?))
+        } else {
+            Ok(Node::Internal(NodeView::split(bytes, m)?))
+        }
+    }
+    pub fn header(&self) -> &'a NodeHeader {
+        match self {
+            Node::Leaf(nodeview) => nodeview.header,
+            Node::Internal(nodeview) => nodeview.header,
+        }
+    }
+}
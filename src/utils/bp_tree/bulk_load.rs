@@ -0,0 +1,224 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+
+use crate::utils::bp_tree::bp_tree_node::{Child, NodeHeader, NodeViewMut};
+use crate::utils::bp_tree::{BPTree, BPTreeError, NodeItem};
+use crate::utils::cache::Cache;
+
+impl<D, C, A, K, V> BPTree<D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    /// Builds the tree bottom-up from entries sorted by strictly increasing key.
+    ///
+    /// Every node is packed to `fill_factor` of its capacity, except that the last two nodes of
+    /// a level are evened out so neither drops below the minimum occupancy. The tree must be
+    /// empty, and is left as it was if the load fails, for instance because the input turns out
+    /// not to be sorted.
+    pub fn bulk_load(
+        &mut self,
+        iter: impl IntoIterator<Item = (K, V)>,
+        fill_factor: f64,
+    ) -> Result<(), BPTreeError> {
+        if self.is_block_manager() {
+            return Err(BPTreeError::IllegalUse);
+        }
+        if self.read_only {
+            return Err(BPTreeError::ReadOnly);
+        }
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(BPTreeError::InvalidFillFactor(fill_factor));
+        }
+        if self.root_block.is_some() && self.first()?.is_some() {
+            return Err(BPTreeError::NotEmpty);
+        }
+
+        // An emptied tree keeps its root until the new nodes are all written, so the
+        // descriptor never names a block that has been given back.
+        let old_root = self.root_block;
+        let old_first_leaf = self.first_leaf;
+        let mut written = Vec::new();
+        let (root_block, height, num_keys) = match self.load_sorted(iter, fill_factor, &mut written)
+        {
+            Ok(Some(tree)) => tree,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                self.first_leaf = old_first_leaf;
+                for block_idx in written {
+                    self.free_node(block_idx)?;
+                }
+                return Err(e);
+            }
+        };
+        if let Some(old_root) = old_root {
+            self.retired.push(old_root);
+        }
+        self.root_block = Some(root_block);
+        self.height = height;
+        self.num_keys = num_keys;
+        self.write_descriptor()
+    }
+
+    /// Writes the nodes of the new tree, recording every block it takes in `written`, and
+    /// returns its root, height and number of keys, or `None` for empty input.
+    fn load_sorted(
+        &mut self,
+        iter: impl IntoIterator<Item = (K, V)>,
+        fill_factor: f64,
+        written: &mut Vec<u64>,
+    ) -> Result<Option<(u64, u64, u64)>, BPTreeError> {
+        let max_keys = (self.m - 1) as usize;
+        let min_keys = self.min_keys() as usize;
+        let cap = ((max_keys as f64 * fill_factor) as usize).clamp(min_keys.max(1), max_keys);
+
+        // Leaves are written as soon as a later one is known to hold at least `cap` entries, so
+        // only the last two are ever buffered.
+        let mut level: Vec<(K, Child)> = Vec::new();
+        let mut buffer: Vec<(K, V)> = Vec::with_capacity(2 * cap);
+        let mut last_key = None;
+        for (index, (key, val)) in iter.into_iter().enumerate() {
+            if last_key.is_some_and(|last| last >= key) {
+                return Err(BPTreeError::UnsortedInput {
+                    index: index as u64,
+                });
+            }
+            last_key = Some(key);
+            buffer.push((key, val));
+            if buffer.len() == 2 * cap {
+                let rest = buffer.split_off(cap);
+                self.write_leaf(&buffer, &mut level, written)?;
+                buffer = rest;
+            }
+        }
+        if buffer.is_empty() && level.is_empty() {
+            return Ok(None);
+        }
+        let num_keys = (level.len() * cap + buffer.len()) as u64;
+        let mut start = 0;
+        for size in Self::plan_nodes(buffer.len(), cap, min_keys) {
+            self.write_leaf(&buffer[start..start + size], &mut level, written)?;
+            start += size;
+        }
+
+        let mut height = 1;
+        while level.len() > 1 {
+            let mut upper = Vec::new();
+            let mut start = 0;
+            let mut prev = u64::MAX;
+            for size in Self::plan_nodes(level.len(), cap + 1, min_keys + 1) {
+                let children = &level[start..start + size];
+                let block_idx = self.write_internal(children, prev, height as u16, written)?;
+                let count = children.iter().map(|(_, child)| child.count.get()).sum();
+                upper.push((children[0].0, Child::new(block_idx, count)));
+                prev = block_idx;
+                start += size;
+            }
+            level = upper;
+            height += 1;
+        }
+        Ok(Some((level[0].1.block.get(), height, num_keys)))
+    }
+
+    /// Splits `total` items into node sizes of `cap`, evening out the last two nodes when the
+    /// final one would fall below `min`.
+    fn plan_nodes(total: usize, cap: usize, min: usize) -> Vec<usize> {
+        let mut sizes = vec![cap; total / cap];
+        let rest = total % cap;
+        if rest > 0 {
+            sizes.push(rest);
+        }
+        if sizes.len() >= 2 && rest > 0 && rest < min {
+            sizes.pop();
+            sizes.pop();
+            let pair = cap + rest;
+            if pair >= 2 * min {
+                sizes.push(pair / 2);
+                sizes.push(pair - pair / 2);
+            } else {
+                sizes.push(pair);
+            }
+        }
+        sizes
+    }
+
+    /// Writes one leaf after the last one in `leaves`, recording its first key and block there.
+    fn write_leaf(
+        &mut self,
+        entries: &[(K, V)],
+        leaves: &mut Vec<(K, Child)>,
+        written: &mut Vec<u64>,
+    ) -> Result<(), BPTreeError> {
+        let prev = leaves
+            .last()
+            .map_or(u64::MAX, |(_, child)| child.block.get());
+        let block_idx = if prev == u64::MAX {
+            self.alloc()?
+        } else {
+            self.alloc_node_near(prev)?
+        };
+        written.push(block_idx);
+
+        let mut ioc = self.io_context.borrow_mut();
+        {
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let header = self.new_header(0, entries.len() as u64);
+            let nodeview = NodeViewMut::<K, V>::init(&mut block, self.m, header)?;
+            for (i, &(key, val)) in entries.iter().enumerate() {
+                nodeview.keys[i] = key;
+                nodeview.vals[i] = val;
+            }
+            nodeview.header.prev = prev.into();
+        }
+        if prev == u64::MAX {
+            self.first_leaf = block_idx;
+        } else {
+            let block = ioc.get_mut(prev)?;
+            let mut block = block.get();
+            NodeHeader::from_bytes_mut(&mut block)?.next = block_idx.into();
+        }
+        leaves.push((entries[0].0, Child::new(block_idx, entries.len() as u64)));
+        Ok(())
+    }
+
+    /// Writes an internal node over `children`, given as `(first key, child)` pairs, and links
+    /// it after `prev` on its level.
+    fn write_internal(
+        &mut self,
+        children: &[(K, Child)],
+        prev: u64,
+        level: u16,
+        written: &mut Vec<u64>,
+    ) -> Result<u64, BPTreeError> {
+        let block_idx = self.alloc_node_near(children[0].1.block.get())?;
+        written.push(block_idx);
+
+        let mut ioc = self.io_context.borrow_mut();
+        {
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let header = self.new_header(level, children.len() as u64 - 1);
+            let nodeview = NodeViewMut::<K, Child>::init(&mut block, self.m, header)?;
+            for (i, &(key, child)) in children.iter().enumerate() {
+                if i > 0 {
+                    nodeview.keys[i - 1] = key;
+                }
+                nodeview.vals[i] = child;
+            }
+            nodeview.header.prev = prev.into();
+        }
+        if prev != u64::MAX {
+            let block = ioc.get_mut(prev)?;
+            let mut block = block.get();
+            NodeHeader::from_bytes_mut(&mut block)?.next = block_idx.into();
+        }
+        Ok(block_idx)
+    }
+}
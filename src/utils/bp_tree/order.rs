@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::ops::{Bound, RangeBounds};
+use std::rc::Rc;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::utils::bp_tree::bp_tree_node::Node;
+use crate::utils::bp_tree::{BPTree, BPTreeError, NodeItem};
+use crate::utils::cache::Cache;
+
+impl<D, C, A, K, V> BPTree<D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    /// Number of keys that fall in `bounds`, found from the counts kept in internal nodes
+    /// rather than by a scan.
+    pub fn count(&self, bounds: impl RangeBounds<K>) -> Result<u64, BPTreeError> {
+        let start = match bounds.start_bound() {
+            Bound::Included(&key) => self.keys_below(key, false)?,
+            Bound::Excluded(&key) => self.keys_below(key, true)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match bounds.end_bound() {
+            Bound::Included(&key) => self.keys_below(key, true)?,
+            Bound::Excluded(&key) => self.keys_below(key, false)?,
+            Bound::Unbounded => self.num_keys,
+        };
+        Ok(end.saturating_sub(start))
+    }
+
+    /// Number of keys less than `key`, which is also the index `key` has or would have in key
+    /// order.
+    pub fn rank(&self, key: K) -> Result<u64, BPTreeError> {
+        self.keys_below(key, false)
+    }
+
+    /// Entry with index `idx` in key order, or `None` past the end.
+    pub fn select(&self, mut idx: u64) -> Result<Option<(K, V)>, BPTreeError> {
+        let Some(mut cur_block) = self.root_block.filter(|_| idx < self.num_keys) else {
+            return Ok(None);
+        };
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let nodeview = match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                Node::Leaf(nodeview) => {
+                    if idx >= nodeview.header.num_keys.get() {
+                        return Err(BPTreeError::Corrupted { block: cur_block });
+                    }
+                    return Ok(Some((
+                        nodeview.keys[idx as usize],
+                        nodeview.vals[idx as usize],
+                    )));
+                }
+                Node::Internal(nodeview) => nodeview,
+            };
+            let num_keys = nodeview.header.num_keys.get();
+            let mut next = None;
+            for child in &nodeview.vals[..=num_keys as usize] {
+                if idx < child.count.get() {
+                    next = Some(child.block.get());
+                    break;
+                }
+                idx -= child.count.get();
+            }
+            cur_block = next.ok_or(BPTreeError::Corrupted { block: cur_block })?;
+        }
+    }
+
+    /// Number of keys less than `key`, or not greater than it if `inclusive`.
+    fn keys_below(&self, key: K, inclusive: bool) -> Result<u64, BPTreeError> {
+        let Some(mut cur_block) = self.root_block else {
+            return Ok(0);
+        };
+        let mut below = 0;
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let nodeview = match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                Node::Leaf(nodeview) => {
+                    let num_keys = nodeview.header.num_keys.get() as usize;
+                    let idx = nodeview.keys[..num_keys]
+                        .partition_point(|x| if inclusive { *x <= key } else { *x < key });
+                    return Ok(below + idx as u64);
+                }
+                Node::Internal(nodeview) => nodeview,
+            };
+
+            // Every child left of the one `key` routes to only holds keys below the separator
+            // that bounds it, which is not greater than `key`.
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            let idx = nodeview.keys[..num_keys].partition_point(|x| *x <= key);
+            below += Self::count_children(&nodeview.vals[..idx]);
+            cur_block = nodeview.vals[idx].block.get();
+        }
+    }
+}
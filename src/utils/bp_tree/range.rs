@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+use std::rc::Rc;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use zerocopy::little_endian::U64;
+
+use crate::utils::bp_tree::bp_tree_node::NodeView;
+use crate::utils::bp_tree::{BPTree, BPTreeError, NodeItem};
+use crate::utils::cache::Cache;
+
+/// Iterator over the `(key, val)` pairs of a `BPTree` within a key range, in key order.
+///
+/// Only the leaves currently being walked are buffered; further ones are read through the
+/// `IOContext` once a buffer runs dry. The front follows `next` links and the back follows
+/// `prev` links, or a descent from the root in copy-on-write trees. Each yielded key tightens
+/// the opposite bound, so the two ends stop where they meet.
+pub struct Range<'a, D, C, A, K = U64, V = U64>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    tree: &'a BPTree<D, C, A, K, V>,
+    lower: Bound<K>,
+    upper: Bound<K>,
+    front: VecDeque<(K, V)>,
+    back: VecDeque<(K, V)>,
+    next_leaf: Option<u64>,
+    prev_leaf: Option<u64>,
+    done: bool,
+}
+
+impl<'a, D, C, A, K, V> Range<'a, D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    pub(super) fn new(tree: &'a BPTree<D, C, A, K, V>, bounds: impl RangeBounds<K>) -> Self {
+        Self {
+            tree,
+            lower: bounds.start_bound().cloned(),
+            upper: bounds.end_bound().cloned(),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            next_leaf: None,
+            prev_leaf: None,
+            done: tree.root_block.is_none(),
+        }
+    }
+
+    fn in_bounds(&self, key: K) -> bool {
+        (self.lower, self.upper).contains(&key)
+    }
+
+    /// Reads the next leaf into the front buffer, returning `false` once the chain ends.
+    fn load_front(&mut self) -> Result<bool, BPTreeError> {
+        let leaf = match self.next_leaf {
+            Some(leaf) => leaf,
+            None => match self.lower {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.leaf_for(key)?,
+                Bound::Unbounded => self.tree.first_leaf,
+            },
+        };
+        if leaf == u64::MAX {
+            return Ok(false);
+        }
+
+        let (next, last_key) = {
+            let mut ioc = self.tree.io_context.borrow_mut();
+            let block = ioc.get(leaf)?;
+            let block = block.get();
+            let nodeview =
+                NodeView::<K, V>::get_from_bytes(&block, self.tree.m, self.tree.check())?;
+
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            for i in 0..num_keys {
+                let key = nodeview.keys[i];
+                if self.in_bounds(key) {
+                    self.front.push_back((key, nodeview.vals[i]));
+                }
+            }
+            (
+                nodeview.header.next.get(),
+                nodeview.keys[..num_keys].last().copied(),
+            )
+        };
+
+        let past_end = last_key.is_some_and(|last| match self.upper {
+            Bound::Included(end) | Bound::Excluded(end) => last >= end,
+            Bound::Unbounded => false,
+        });
+        self.next_leaf = Some(if past_end {
+            u64::MAX
+        } else {
+            self.tree.next_leaf(next, last_key)?
+        });
+        Ok(true)
+    }
+
+    /// Reads the previous leaf into the back buffer, returning `false` once the chain ends.
+    fn load_back(&mut self) -> Result<bool, BPTreeError> {
+        let leaf = match self.prev_leaf {
+            Some(leaf) => leaf,
+            None => match self.upper {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.leaf_for(key)?,
+                Bound::Unbounded => self.tree.rightmost_leaf()?,
+            },
+        };
+        if leaf == u64::MAX {
+            return Ok(false);
+        }
+
+        let (prev, first_key) = {
+            let mut ioc = self.tree.io_context.borrow_mut();
+            let block = ioc.get(leaf)?;
+            let block = block.get();
+            let nodeview =
+                NodeView::<K, V>::get_from_bytes(&block, self.tree.m, self.tree.check())?;
+
+            let num_keys = nodeview.header.num_keys.get() as usize;
+            for i in (0..num_keys).rev() {
+                let key = nodeview.keys[i];
+                if self.in_bounds(key) {
+                    self.back.push_back((key, nodeview.vals[i]));
+                }
+            }
+            (
+                nodeview.header.prev.get(),
+                nodeview.keys[..num_keys].first().copied(),
+            )
+        };
+
+        let past_start = first_key.is_some_and(|first| match self.lower {
+            Bound::Included(start) | Bound::Excluded(start) => first <= start,
+            Bound::Unbounded => false,
+        });
+        self.prev_leaf = Some(if past_start {
+            u64::MAX
+        } else {
+            self.tree.prev_leaf(prev, first_key)?
+        });
+        Ok(true)
+    }
+
+    fn finish(&mut self, e: Option<BPTreeError>) -> Option<<Self as Iterator>::Item> {
+        self.done = true;
+        self.front.clear();
+        self.back.clear();
+        e.map(Err)
+    }
+}
+
+impl<D, C, A, K, V> Iterator for Range<'_, D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    type Item = Result<(K, V), BPTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((key, val)) = self.front.pop_front() {
+                if !self.in_bounds(key) {
+                    return self.finish(None);
+                }
+                self.lower = Bound::Excluded(key);
+                return Some(Ok((key, val)));
+            }
+            match self.load_front() {
+                Ok(true) => {}
+                Ok(false) => return self.finish(None),
+                Err(e) => return self.finish(Some(e)),
+            }
+        }
+        None
+    }
+}
+
+impl<D, C, A, K, V> DoubleEndedIterator for Range<'_, D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some((key, val)) = self.back.pop_front() {
+                if !self.in_bounds(key) {
+                    return self.finish(None);
+                }
+                self.upper = Bound::Excluded(key);
+                return Some(Ok((key, val)));
+            }
+            match self.load_back() {
+                Ok(true) => {}
+                Ok(false) => return self.finish(None),
+                Err(e) => return self.finish(Some(e)),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::utils::bp_tree::bp_tree_node::Node;
+use crate::utils::bp_tree::{BPTree, BPTreeError, NodeItem};
+use crate::utils::cache::Cache;
+
+/// Shape of a tree as counted by `BPTree::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    pub height: u64,
+    pub internal_nodes: u64,
+    pub leaf_nodes: u64,
+    pub num_keys: u64,
+    /// One entry per level, from the root down to the leaves.
+    pub levels: Vec<LevelStats>,
+    /// Nodes plus the descriptor block, if the tree has one.
+    pub blocks_used: u64,
+}
+
+/// Occupancy of one level, with fill measured against the `m - 1` keys a node can hold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelStats {
+    pub nodes: u64,
+    pub keys: u64,
+    pub avg_fill: f64,
+    pub min_fill: f64,
+}
+
+impl<D, C, A, K, V> BPTree<D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    /// Walks the tree one level at a time and counts its nodes, keys and fill.
+    pub fn stats(&self) -> Result<TreeStats, BPTreeError> {
+        let mut stats = TreeStats {
+            height: self.height,
+            internal_nodes: 0,
+            leaf_nodes: 0,
+            num_keys: 0,
+            levels: Vec::new(),
+            blocks_used: self.desc_block.map_or(0, |_| 1),
+        };
+        let max_keys = (self.m - 1) as f64;
+
+        let mut level = self.root_block.into_iter().collect::<Vec<_>>();
+        while !level.is_empty() {
+            let mut children = Vec::new();
+            let mut keys = 0;
+            let mut min_keys = u64::MAX;
+            let mut is_leaf = false;
+            {
+                let mut ioc = self.io_context.borrow_mut();
+                for &block_idx in &level {
+                    let block = ioc.get(block_idx)?;
+                    let block = block.get();
+                    let header = match Node::<K, V>::get_from_bytes(&block, self.m, self.check())? {
+                        Node::Leaf(nodeview) => nodeview.header,
+                        Node::Internal(nodeview) => {
+                            let num_keys = nodeview.header.num_keys.get() as usize;
+                            children.extend(
+                                nodeview.vals[..=num_keys]
+                                    .iter()
+                                    .map(|child| child.block.get()),
+                            );
+                            nodeview.header
+                        }
+                    };
+                    let num_keys = header.num_keys.get();
+                    keys += num_keys;
+                    min_keys = min_keys.min(num_keys);
+                    is_leaf = header.is_leaf == 1;
+                }
+            }
+
+            let nodes = level.len() as u64;
+            if is_leaf {
+                stats.leaf_nodes += nodes;
+                stats.num_keys += keys;
+            } else {
+                stats.internal_nodes += nodes;
+            }
+            stats.levels.push(LevelStats {
+                nodes,
+                keys,
+                avg_fill: keys as f64 / nodes as f64 / max_keys,
+                min_fill: min_keys as f64 / max_keys,
+            });
+            level = children;
+        }
+        stats.blocks_used += stats.internal_nodes + stats.leaf_nodes;
+        Ok(stats)
+    }
+}
@@ -0,0 +1,319 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::utils::bp_tree::bp_tree_node::{Node, NodeParseError};
+use crate::utils::bp_tree::{BPTree, BPTreeError, NodeItem};
+use crate::utils::cache::Cache;
+
+/// Broken invariant found by `BPTree::verify`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    #[error("Keys of node {block} are not strictly increasing at index {idx}")]
+    UnsortedKeys { block: u64, idx: usize },
+    #[error("Key {idx} of node {block} lies outside the separators that route to it")]
+    OutOfBounds { block: u64, idx: usize },
+    #[error("Node {block} holds {num_keys} keys, more than the {max} that fit")]
+    Overfull { block: u64, num_keys: u64, max: u64 },
+    #[error("Node {block} holds {num_keys} keys, fewer than the minimum of {min}")]
+    Underfull { block: u64, num_keys: u64, min: u64 },
+    #[error("Child {idx} of node {block} points at {child}, past the end of the disk")]
+    BadChild { block: u64, idx: usize, child: u64 },
+    #[error("Node {block} records level {found}, but sits at level {expected}")]
+    Level {
+        block: u64,
+        expected: u64,
+        found: u64,
+    },
+    #[error(
+        "Child {idx} of node {block} is recorded as holding {found} keys, but holds {expected}"
+    )]
+    SubtreeCount {
+        block: u64,
+        idx: usize,
+        expected: u64,
+        found: u64,
+    },
+    #[error("Block {block} is reachable more than once")]
+    SharedBlock { block: u64 },
+    #[error("Node {block} cannot be read: {error}")]
+    Unreadable { block: u64, error: NodeParseError },
+    #[error("Leaf {block} is at depth {depth}, but the first leaf is at depth {expected}")]
+    UnevenDepth {
+        block: u64,
+        depth: u64,
+        expected: u64,
+    },
+    #[error("Leaf {block} links to {found} as next, but the next leaf is {expected}")]
+    NextLink {
+        block: u64,
+        expected: u64,
+        found: u64,
+    },
+    #[error("Leaf {block} links to {found} as prev, but the previous leaf is {expected}")]
+    PrevLink {
+        block: u64,
+        expected: u64,
+        found: u64,
+    },
+    #[error("Tree records {found} as its first leaf, but the leftmost leaf is {expected}")]
+    FirstLeaf { expected: u64, found: u64 },
+    #[error("Tree records a height of {found}, but its leaves are at depth {expected}")]
+    Height { expected: u64, found: u64 },
+    #[error("Tree records {found} keys, but its leaves hold {expected}")]
+    KeyCount { expected: u64, found: u64 },
+}
+
+/// Leaves in the order the walk reached them, with their sibling links.
+struct LeafLink {
+    block: u64,
+    next: u64,
+    prev: u64,
+}
+
+/// State carried through the walk.
+struct Walk {
+    visited: HashSet<u64>,
+    leaves: Vec<LeafLink>,
+    leaf_depth: Option<u64>,
+    num_keys: u64,
+    blocks_count: u64,
+    violations: Vec<Violation>,
+}
+
+impl<D, C, A, K, V> BPTree<D, C, A, K, V>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+    K: NodeItem + Ord,
+    V: NodeItem,
+{
+    /// Walks every node and returns the invariants the tree breaks, or an empty list if it is
+    /// well formed.
+    ///
+    /// Checks key order within nodes, keys against the separators above them, occupancy, node
+    /// levels, the key counts internal nodes keep for their children, that all leaves sit at
+    /// the same depth, that the leaf chain matches the in-order leaf sequence (copy-on-write
+    /// trees do not keep one), that no block is reachable twice, and the counters kept in the
+    /// descriptor. Nodes found unreadable, overfull or shared are reported but not descended
+    /// into, and the walk goes on with the rest of the tree.
+    pub fn verify(&self) -> Result<Vec<Violation>, BPTreeError> {
+        let blocks_count = {
+            let ioc = self.io_context.borrow();
+            ioc.get_disk_capacity() / ioc.get_disk_block_size()
+        };
+        let mut walk = Walk {
+            visited: self.desc_block.into_iter().collect(),
+            leaves: Vec::new(),
+            leaf_depth: None,
+            num_keys: 0,
+            blocks_count,
+            violations: Vec::new(),
+        };
+
+        let Some(root_block) = self.root_block else {
+            if self.num_keys != 0 {
+                walk.violations.push(Violation::KeyCount {
+                    expected: 0,
+                    found: self.num_keys,
+                });
+            }
+            return Ok(walk.violations);
+        };
+        self.verify_node(&mut walk, root_block, None, None, 1)?;
+
+        // Copy-on-write trees leave sibling links stale.
+        let leaves = &walk.leaves;
+        for (i, leaf) in leaves.iter().enumerate().filter(|_| !self.cow) {
+            let prev = i.checked_sub(1).map_or(u64::MAX, |i| leaves[i].block);
+            let next = leaves.get(i + 1).map_or(u64::MAX, |leaf| leaf.block);
+            if leaf.next != next {
+                walk.violations.push(Violation::NextLink {
+                    block: leaf.block,
+                    expected: next,
+                    found: leaf.next,
+                });
+            }
+            if leaf.prev != prev {
+                walk.violations.push(Violation::PrevLink {
+                    block: leaf.block,
+                    expected: prev,
+                    found: leaf.prev,
+                });
+            }
+        }
+        if let Some(first) = leaves.first()
+            && first.block != self.first_leaf
+        {
+            walk.violations.push(Violation::FirstLeaf {
+                expected: first.block,
+                found: self.first_leaf,
+            });
+        }
+        if let Some(depth) = walk.leaf_depth
+            && depth != self.height
+        {
+            walk.violations.push(Violation::Height {
+                expected: depth,
+                found: self.height,
+            });
+        }
+        if walk.num_keys != self.num_keys {
+            walk.violations.push(Violation::KeyCount {
+                expected: walk.num_keys,
+                found: self.num_keys,
+            });
+        }
+        Ok(walk.violations)
+    }
+
+    /// Checks the subtree rooted at `block_idx`, whose keys must lie in `[lower, upper)`, and
+    /// returns how many keys its leaves hold, or `None` if part of it was not walked.
+    fn verify_node(
+        &self,
+        walk: &mut Walk,
+        block_idx: u64,
+        lower: Option<K>,
+        upper: Option<K>,
+        depth: u64,
+    ) -> Result<Option<u64>, BPTreeError> {
+        if !walk.visited.insert(block_idx) {
+            walk.violations
+                .push(Violation::SharedBlock { block: block_idx });
+            return Ok(None);
+        }
+
+        let is_root = Some(block_idx) == self.root_block;
+        let (is_leaf, keys, children) = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let node = match Node::<K, V>::get_from_bytes(&block, self.m, self.check()) {
+                Ok(node) => node,
+                Err(error) => {
+                    walk.violations.push(Violation::Unreadable {
+                        block: block_idx,
+                        error,
+                    });
+                    return Ok(None);
+                }
+            };
+            let header = node.header();
+            let num_keys = header.num_keys.get();
+            if num_keys > self.m - 1 {
+                walk.violations.push(Violation::Overfull {
+                    block: block_idx,
+                    num_keys,
+                    max: self.m - 1,
+                });
+                return Ok(None);
+            }
+            let level = self.height.saturating_sub(depth);
+            if u64::from(header.level.get()) != level {
+                walk.violations.push(Violation::Level {
+                    block: block_idx,
+                    expected: level,
+                    found: header.level.get().into(),
+                });
+            }
+            let min = match (is_root, header.is_leaf == 1) {
+                (false, _) => self.min_keys(),
+                (true, false) => 1,
+                (true, true) => 0,
+            };
+            if num_keys < min {
+                walk.violations.push(Violation::Underfull {
+                    block: block_idx,
+                    num_keys,
+                    min,
+                });
+            }
+
+            let num_keys = num_keys as usize;
+            match node {
+                Node::Leaf(nodeview) => {
+                    walk.leaves.push(LeafLink {
+                        block: block_idx,
+                        next: header.next.get(),
+                        prev: header.prev.get(),
+                    });
+                    (true, nodeview.keys[..num_keys].to_vec(), Vec::new())
+                }
+                Node::Internal(nodeview) => (
+                    false,
+                    nodeview.keys[..num_keys].to_vec(),
+                    nodeview.vals[..num_keys + 1].to_vec(),
+                ),
+            }
+        };
+
+        for (idx, pair) in keys.windows(2).enumerate() {
+            if pair[0] >= pair[1] {
+                walk.violations.push(Violation::UnsortedKeys {
+                    block: block_idx,
+                    idx: idx + 1,
+                });
+            }
+        }
+        for (idx, key) in keys.iter().enumerate() {
+            if lower.is_some_and(|lower| *key < lower) || upper.is_some_and(|upper| *key >= upper) {
+                walk.violations.push(Violation::OutOfBounds {
+                    block: block_idx,
+                    idx,
+                });
+            }
+        }
+
+        if is_leaf {
+            walk.num_keys += keys.len() as u64;
+            match walk.leaf_depth {
+                None => walk.leaf_depth = Some(depth),
+                Some(expected) if expected != depth => {
+                    walk.violations.push(Violation::UnevenDepth {
+                        block: block_idx,
+                        depth,
+                        expected,
+                    });
+                }
+                Some(_) => {}
+            }
+            return Ok(Some(keys.len() as u64));
+        }
+
+        let mut count = Some(0);
+        for (idx, child) in children.iter().enumerate() {
+            let (child, found) = (child.block.get(), child.count.get());
+            if child >= walk.blocks_count {
+                walk.violations.push(Violation::BadChild {
+                    block: block_idx,
+                    idx,
+                    child,
+                });
+                count = None;
+                continue;
+            }
+            let child_lower = idx.checked_sub(1).map(|i| keys[i]).or(lower);
+            let child_upper = keys.get(idx).copied().or(upper);
+            let expected = self.verify_node(walk, child, child_lower, child_upper, depth + 1)?;
+            if let Some(expected) = expected
+                && expected != found
+            {
+                walk.violations.push(Violation::SubtreeCount {
+                    block: block_idx,
+                    idx,
+                    expected,
+                    found,
+                });
+            }
+            count = count
+                .zip(expected)
+                .map(|(count, expected)| count + expected);
+        }
+        Ok(count)
+    }
+}
@@ -1,12 +1,43 @@
+use std::rc::Rc;
+
+use thiserror::Error;
+
 pub mod lru;
 
 pub trait Cache<K, V> {
     fn new(cap: u64) -> Self;
-    fn put(&mut self, key: K, val: V, dirty: bool) -> Option<(K, V, bool)>;
+    /// Inserts `val`, evicting the least recently used entry that is not pinned if the cache is
+    /// full. The evicted entry is handed back so it can be written out if dirty.
+    fn put(&mut self, key: K, val: V, dirty: bool) -> Result<Option<(K, V, bool)>, CacheError>;
     fn get(&mut self, key: &K, dirty: bool) -> Option<&V>;
     fn mark_dirty(&mut self, key: &K) -> bool;
+    fn mark_clean(&mut self, key: &K) -> bool;
+    /// Entries written to since they were put or last marked clean, in no particular order.
+    fn dirty<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a;
     fn is_empty(&self) -> bool;
     fn drain(&mut self) -> impl Iterator<Item = (K, V, bool)>;
     fn peek(&self, key: K) -> Option<&V>;
     fn clear(&mut self);
 }
+
+/// Cached value that can still be in use outside the cache, in which case evicting it would
+/// lose whatever is written to it afterwards.
+pub trait Pinnable {
+    fn is_pinned(&self) -> bool;
+}
+
+/// Pinned for as long as anyone besides the cache holds a clone, such as a `MutableBlock`.
+impl<T> Pinnable for Rc<T> {
+    fn is_pinned(&self) -> bool {
+        Rc::strong_count(self) > 1
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("Every cache entry is pinned")]
+    AllPinned,
+}
@@ -1,6 +1,6 @@
 use ahash::AHashMap;
 
-use super::Cache;
+use super::{Cache, CacheError, Pinnable};
 use std::fmt::Debug;
 use std::hash::Hash;
 
@@ -20,7 +20,7 @@ pub struct LRU<K, V> {
     head: Option<u64>,
 }
 
-impl<K: Clone + Hash + Eq, V> Cache<K, V> for LRU<K, V> {
+impl<K: Clone + Hash + Eq, V: Pinnable> Cache<K, V> for LRU<K, V> {
     fn new(cap: u64) -> Self {
         assert!(cap > 0);
         Self {
@@ -31,12 +31,12 @@ impl<K: Clone + Hash + Eq, V> Cache<K, V> for LRU<K, V> {
         }
     }
 
-    fn put(&mut self, key: K, val: V, dirty: bool) -> Option<(K, V, bool)> {
+    fn put(&mut self, key: K, val: V, dirty: bool) -> Result<Option<(K, V, bool)>, CacheError> {
         if let Some(&idx) = self.map.get(&key) {
             self.nodes[idx as usize].val = val;
             self.nodes[idx as usize].dirty = dirty;
             self.move_to_head(idx);
-            return None;
+            return Ok(None);
         }
 
         if self.nodes.len() < self.cap as usize {
@@ -49,17 +49,24 @@ impl<K: Clone + Hash + Eq, V> Cache<K, V> for LRU<K, V> {
                 next: idx,
             });
 
-            if let Some(_) = self.head {
+            if self.head.is_some() {
                 self.move_to_head(idx);
             } else {
                 self.head = Some(idx);
             }
             self.map.insert(key, idx);
-            return None;
+            return Ok(None);
         }
 
+        // Walk from the tail towards the head for the first entry nobody else holds.
         let head = self.head.unwrap();
-        let idx = self.nodes[head as usize].prev as usize;
+        let mut idx = self.nodes[head as usize].prev as usize;
+        while self.nodes[idx].val.is_pinned() {
+            if idx == head as usize {
+                return Err(CacheError::AllPinned);
+            }
+            idx = self.nodes[idx].prev as usize;
+        }
 
         let old_key = std::mem::replace(&mut self.nodes[idx].key, key.clone());
         let old_val = std::mem::replace(&mut self.nodes[idx].val, val);
@@ -69,7 +76,7 @@ impl<K: Clone + Hash + Eq, V> Cache<K, V> for LRU<K, V> {
         self.map.insert(key, idx as u64);
 
         self.move_to_head(idx as u64);
-        return Some((old_key, old_val, old_dirty));
+        Ok(Some((old_key, old_val, old_dirty)))
     }
 
     fn get(&mut self, key: &K, dirty: bool) -> Option<&V> {
@@ -79,7 +86,7 @@ impl<K: Clone + Hash + Eq, V> Cache<K, V> for LRU<K, V> {
             if dirty {
                 self.nodes[idx as usize].dirty = true;
             }
-            Some(&mut self.nodes[idx as usize].val)
+            Some(&self.nodes[idx as usize].val)
         } else {
             None
         }
@@ -93,6 +100,25 @@ impl<K: Clone + Hash + Eq, V> Cache<K, V> for LRU<K, V> {
         false
     }
 
+    fn mark_clean(&mut self, key: &K) -> bool {
+        if let Some(&idx) = self.map.get(key) {
+            self.nodes[idx as usize].dirty = false;
+            return true;
+        }
+        false
+    }
+
+    fn dirty<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        self.nodes
+            .iter()
+            .filter(|node| node.dirty)
+            .map(|node| (&node.key, &node.val))
+    }
+
     fn is_empty(&self) -> bool {
         self.head.is_none()
     }
@@ -0,0 +1,976 @@
+use std::cell::RefCell;
+use std::ops::RangeBounds;
+use std::rc::Rc;
+
+mod overflow;
+mod range;
+mod slotted_node;
+
+pub use range::Range;
+
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::io_context::IOContext;
+use crate::utils::bp_tree::BPTreeError;
+use crate::utils::cache::Cache;
+use crate::utils::var_bp_tree::overflow::read_overflow;
+use crate::utils::var_bp_tree::slotted_node::{
+    Cell, CellHeader, OverflowRef, SLOT_SIZE, SlottedNode, SlottedNodeMut, VAR_TREE_MAGIC,
+    VarTreeDescriptor, capacity,
+};
+
+/// Separator and block of the right sibling created by a split.
+type Split = (Vec<u8>, u64);
+
+/// B+ tree over byte-string keys and values, stored in slotted nodes.
+///
+/// Every cell takes at most a quarter of a node, so a split always leaves both halves with
+/// room to spare. Values that would push a cell past that are moved to a chain of overflow
+/// blocks; keys have to fit inline, see `max_key_len`. Only leaves are linked to their
+/// siblings.
+pub struct VarBPTree<D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    io_context: Rc<RefCell<IOContext<D, C>>>,
+    allocator: Rc<RefCell<A>>,
+    /// Block holding the on-disk `VarTreeDescriptor`, for trees that outlive an unmount.
+    desc_block: Option<u64>,
+    root_block: Option<u64>,
+    first_leaf: u64,
+    height: u64,
+    num_keys: u64,
+    block_size: usize,
+    /// Blocks set aside by `reserve_splits` for the splits an insert may cause.
+    reserved: Vec<u64>,
+}
+
+impl<D, C, A> VarBPTree<D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+{
+    /// Creates an empty tree that lives only as long as this handle.
+    pub fn new(ioc: Rc<RefCell<IOContext<D, C>>>, allocator: Rc<RefCell<A>>) -> Self {
+        let block_size = ioc.borrow().get_disk_block_size() as usize;
+        Self {
+            io_context: ioc,
+            allocator,
+            desc_block: None,
+            root_block: None,
+            first_leaf: u64::MAX,
+            height: 0,
+            num_keys: 0,
+            block_size,
+            reserved: Vec::new(),
+        }
+    }
+
+    /// Creates an empty tree whose descriptor lives in `desc_block`.
+    pub fn create(
+        desc_block: u64,
+        ioc: Rc<RefCell<IOContext<D, C>>>,
+        allocator: Rc<RefCell<A>>,
+    ) -> Result<Self, BPTreeError> {
+        let mut tree = Self::new(ioc, allocator);
+        tree.desc_block = Some(desc_block);
+        tree.write_descriptor()?;
+        Ok(tree)
+    }
+
+    /// Reopens the tree whose descriptor was written to `desc_block`.
+    pub fn open(
+        desc_block: u64,
+        ioc: Rc<RefCell<IOContext<D, C>>>,
+        allocator: Rc<RefCell<A>>,
+    ) -> Result<Self, BPTreeError> {
+        let mut tree = Self::new(ioc, allocator);
+        {
+            let mut ioc = tree.io_context.borrow_mut();
+            let block = ioc.get(desc_block)?;
+            let block = block.get();
+            let (desc, _) = VarTreeDescriptor::ref_from_prefix(&block)
+                .map_err(|_| BPTreeError::BadDescriptor { block: desc_block })?;
+            if desc.magic.get() != VAR_TREE_MAGIC || desc.block_size.get() != tree.block_size as u64
+            {
+                return Err(BPTreeError::BadDescriptor { block: desc_block });
+            }
+            tree.root_block = Some(desc.root.get()).filter(|&root| root != u64::MAX);
+            tree.first_leaf = desc.first_leaf.get();
+            tree.height = desc.height.get();
+            tree.num_keys = desc.num_keys.get();
+        }
+        tree.desc_block = Some(desc_block);
+        Ok(tree)
+    }
+
+    /// Writes the root, first leaf and counters back to the descriptor block, if there is one.
+    fn write_descriptor(&self) -> Result<(), BPTreeError> {
+        let Some(desc_block) = self.desc_block else {
+            return Ok(());
+        };
+        let desc = VarTreeDescriptor {
+            magic: VAR_TREE_MAGIC.into(),
+            root: self.root_block.unwrap_or(u64::MAX).into(),
+            first_leaf: self.first_leaf.into(),
+            height: self.height.into(),
+            num_keys: self.num_keys.into(),
+            block_size: (self.block_size as u64).into(),
+        };
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get_mut(desc_block)?;
+        let mut block = block.get();
+        desc.write_to_prefix(&mut block)
+            .map_err(|_| BPTreeError::BadDescriptor { block: desc_block })?;
+        Ok(())
+    }
+
+    /// Largest cell a node accepts, slot included.
+    fn max_cell(&self) -> usize {
+        capacity(self.block_size) / 4
+    }
+
+    /// Longest key `insert` accepts.
+    pub fn max_key_len(&self) -> usize {
+        self.max_cell() - SLOT_SIZE - size_of::<CellHeader>() - size_of::<OverflowRef>()
+    }
+
+    fn node<'b>(&self, bytes: &'b [u8], block_idx: u64) -> Result<SlottedNode<'b>, BPTreeError> {
+        SlottedNode::get_from_bytes(bytes).ok_or(BPTreeError::Corrupted { block: block_idx })
+    }
+
+    fn node_mut<'b>(
+        &self,
+        bytes: &'b mut [u8],
+        block_idx: u64,
+    ) -> Result<SlottedNodeMut<'b>, BPTreeError> {
+        SlottedNodeMut::get_from_bytes(bytes).ok_or(BPTreeError::Corrupted { block: block_idx })
+    }
+
+    /// Inserts `key`, replacing the value it had before.
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<(), BPTreeError> {
+        if key.len() > self.max_key_len() {
+            return Err(BPTreeError::KeyTooLong {
+                len: key.len(),
+                max: self.max_key_len(),
+            });
+        }
+        if SLOT_SIZE + size_of::<CellHeader>() + key.len() + val.len() <= self.max_cell() {
+            return self.insert_cell(Cell {
+                key: key.to_vec(),
+                flags: 0,
+                val: val.to_vec(),
+            });
+        }
+
+        let overflow = self.write_overflow(val)?;
+        let cell = Cell {
+            key: key.to_vec(),
+            flags: slotted_node::CELL_OVERFLOW,
+            val: overflow.as_bytes().to_vec(),
+        };
+        let result = self.insert_cell(cell);
+        // The chain goes back unless the cell reached a leaf before the failure. If that can't
+        // be told, it is leaked rather than risk leaving a cell pointing at freed blocks.
+        if result.is_err() && !self.holds_overflow(key, &overflow).unwrap_or(true) {
+            self.free_overflow(&overflow)?;
+        }
+        result
+    }
+
+    fn insert_cell(&mut self, cell: Cell) -> Result<(), BPTreeError> {
+        let Some(root_block) = self.root_block else {
+            let block_idx = self.allocator.borrow_mut().alloc()?;
+            {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get_mut(block_idx)?;
+                let mut block = block.get();
+                SlottedNodeMut::init(&mut block, true).insert(0, &cell);
+            }
+            self.root_block = Some(block_idx);
+            self.first_leaf = block_idx;
+            self.height = 1;
+            self.num_keys = 1;
+            return self.write_descriptor();
+        };
+
+        self.reserve_splits(root_block, &cell)?;
+        let result = self.insert_below_root(root_block, cell);
+        for block_idx in std::mem::take(&mut self.reserved) {
+            self.allocator.borrow_mut().free(block_idx)?;
+        }
+        result
+    }
+
+    /// Allocates a block for every split inserting `cell` may cause, so that running out of
+    /// space fails the insert before any node has changed.
+    ///
+    /// A leaf splits when `cell` does not fit, and an internal node when it could not take the
+    /// largest separator; that overestimates at worst, and the spare blocks are given back.
+    fn reserve_splits(&mut self, root_block: u64, cell: &Cell) -> Result<(), BPTreeError> {
+        let mut full = Vec::new();
+        let mut cur_block = root_block;
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let node = self.node(&block, cur_block)?;
+            if node.is_leaf() {
+                full.push(node.free_space() < cell.size());
+                break;
+            }
+            full.push(node.free_space() < self.max_cell());
+            cur_block = node.child(node.child_index(&cell.key));
+        }
+        let splits = full.iter().rev().take_while(|&&full| full).count();
+        let needed = splits + usize::from(splits == full.len());
+        while self.reserved.len() < needed {
+            let block_idx = self.allocator.borrow_mut().alloc_near(root_block);
+            match block_idx {
+                Ok(block_idx) => self.reserved.push(block_idx),
+                Err(e) => {
+                    for block_idx in std::mem::take(&mut self.reserved) {
+                        self.allocator.borrow_mut().free(block_idx)?;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A reserved block if there is one left, or else a fresh one near `goal`.
+    fn alloc_node_near(&mut self, goal: u64) -> Result<u64, BPTreeError> {
+        match self.reserved.pop() {
+            Some(block_idx) => Ok(block_idx),
+            None => Ok(self.allocator.borrow_mut().alloc_near(goal)?),
+        }
+    }
+
+    fn insert_below_root(&mut self, root_block: u64, cell: Cell) -> Result<(), BPTreeError> {
+        let (split, old) = self.insert_node(root_block, cell)?;
+        if let Some((key, right)) = split {
+            let new_root = self.alloc_node_near(root_block)?;
+            {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get_mut(new_root)?;
+                let mut block = block.get();
+                let mut node = SlottedNodeMut::init(&mut block, false);
+                node.header().leftmost = root_block.into();
+                node.insert(0, &Cell::separator(key, right));
+            }
+            self.root_block = Some(new_root);
+            self.height += 1;
+        }
+        match old {
+            Some(old) => {
+                if let Some(overflow) = old.overflow() {
+                    self.free_overflow(&overflow)?;
+                }
+            }
+            None => self.num_keys += 1,
+        }
+        self.write_descriptor()
+    }
+
+    /// Inserts `cell` below `block_idx`, returning the separator and block of the new right
+    /// sibling if the node had to split, along with the cell it replaced.
+    fn insert_node(
+        &mut self,
+        block_idx: u64,
+        cell: Cell,
+    ) -> Result<(Option<Split>, Option<Cell>), BPTreeError> {
+        let child = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let node = self.node(&block, block_idx)?;
+            if node.is_leaf() {
+                None
+            } else {
+                let idx = node.child_index(&cell.key);
+                Some((idx, node.child(idx)))
+            }
+        };
+        let Some((idx, child)) = child else {
+            return self.insert_leaf(block_idx, cell);
+        };
+
+        let (split, old) = self.insert_node(child, cell)?;
+        let Some((key, right)) = split else {
+            return Ok((None, old));
+        };
+        let separator = Cell::separator(key, right);
+        let cells = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let mut node = self.node_mut(&mut block, block_idx)?;
+            if node.insert(idx, &separator) {
+                return Ok((None, old));
+            }
+            let mut cells = node.view().cells();
+            cells.insert(idx, separator);
+            cells
+        };
+        Ok((Some(self.split_internal(block_idx, cells)?), old))
+    }
+
+    fn insert_leaf(
+        &mut self,
+        block_idx: u64,
+        cell: Cell,
+    ) -> Result<(Option<Split>, Option<Cell>), BPTreeError> {
+        let (cells, old) = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let mut node = self.node_mut(&mut block, block_idx)?;
+            let (idx, old) = match node.view().search(&cell.key) {
+                Ok(idx) => {
+                    let old = node.view().cell(idx);
+                    node.remove(idx);
+                    (idx, Some(old))
+                }
+                Err(idx) => (idx, None),
+            };
+            if node.insert(idx, &cell) {
+                return Ok((None, old));
+            }
+            // Put the old cell back, so the node is unchanged should the split fail.
+            if let Some(old) = &old {
+                node.insert(idx, old);
+            }
+            let mut cells = node.view().cells();
+            match old {
+                Some(_) => cells[idx] = cell,
+                None => cells.insert(idx, cell),
+            }
+            (cells, old)
+        };
+        Ok((Some(self.split_leaf(block_idx, cells)?), old))
+    }
+
+    /// Index at which `cells` splits into two halves of roughly equal size in bytes.
+    fn split_point(cells: &[Cell]) -> usize {
+        let total: usize = cells.iter().map(Cell::size).sum();
+        let mut size = 0;
+        let mut idx = 0;
+        while idx < cells.len() && size < total / 2 {
+            size += cells[idx].size();
+            idx += 1;
+        }
+        idx.clamp(1, cells.len() - 1)
+    }
+
+    /// Rewrites the leaf at `block_idx` with the lower half of `cells` and a new right sibling
+    /// with the upper half, returning the separator and the sibling's block.
+    fn split_leaf(&mut self, block_idx: u64, cells: Vec<Cell>) -> Result<Split, BPTreeError> {
+        let mid = Self::split_point(&cells);
+        let right = self.alloc_node_near(block_idx)?;
+
+        let mut ioc = self.io_context.borrow_mut();
+        let next = {
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let mut node = self.node_mut(&mut block, block_idx)?;
+            node.rebuild(&cells[..mid]);
+            let next = node.header().next.get();
+            node.header().next = right.into();
+            next
+        };
+        {
+            let block = ioc.get_mut(right)?;
+            let mut block = block.get();
+            let mut node = SlottedNodeMut::init(&mut block, true);
+            node.rebuild(&cells[mid..]);
+            node.header().prev = block_idx.into();
+            node.header().next = next.into();
+        }
+        if next != u64::MAX {
+            let block = ioc.get_mut(next)?;
+            let mut block = block.get();
+            self.node_mut(&mut block, next)?.header().prev = right.into();
+        }
+        Ok((cells[mid].key.clone(), right))
+    }
+
+    /// Like `split_leaf`, except that the middle separator moves up instead of being copied.
+    fn split_internal(&mut self, block_idx: u64, cells: Vec<Cell>) -> Result<Split, BPTreeError> {
+        let mid = Self::split_point(&cells).clamp(1, cells.len() - 2);
+        let right = self.alloc_node_near(block_idx)?;
+
+        let mut ioc = self.io_context.borrow_mut();
+        {
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            self.node_mut(&mut block, block_idx)?.rebuild(&cells[..mid]);
+        }
+        {
+            let block = ioc.get_mut(right)?;
+            let mut block = block.get();
+            let mut node = SlottedNodeMut::init(&mut block, false);
+            node.header().leftmost = cells[mid].child().into();
+            node.rebuild(&cells[mid + 1..]);
+        }
+        Ok((cells[mid].key.clone(), right))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BPTreeError> {
+        let Some(mut cur_block) = self.root_block else {
+            return Ok(None);
+        };
+
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let cell = {
+                let block = ioc.get(cur_block)?;
+                let block = block.get();
+                let node = self.node(&block, cur_block)?;
+                if !node.is_leaf() {
+                    cur_block = node.child(node.child_index(key));
+                    continue;
+                }
+                let Ok(idx) = node.search(key) else {
+                    return Ok(None);
+                };
+                node.cell(idx)
+            };
+            return match cell.overflow() {
+                Some(overflow) => Ok(Some(read_overflow(&mut ioc, &overflow)?)),
+                None => Ok(Some(cell.val)),
+            };
+        }
+    }
+
+    /// Removes `key`. A node left less than a quarter full is merged with a sibling, or evens
+    /// out with it when the two do not fit in one node.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, BPTreeError> {
+        let Some(root_block) = self.root_block else {
+            return Ok(None);
+        };
+        let Some(cell) = self.remove_node(root_block, key)? else {
+            return Ok(None);
+        };
+        self.num_keys -= 1;
+        let val = match cell.overflow() {
+            Some(overflow) => {
+                let val = read_overflow(&mut self.io_context.borrow_mut(), &overflow)?;
+                self.free_overflow(&overflow)?;
+                val
+            }
+            None => cell.val,
+        };
+        self.collapse_root()?;
+        self.write_descriptor()?;
+        Ok(Some(val))
+    }
+
+    /// Nodes on the way down are only read; the leaf is changed only if it holds `key`.
+    fn remove_node(&mut self, block_idx: u64, key: &[u8]) -> Result<Option<Cell>, BPTreeError> {
+        let (idx, child) = {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(block_idx)?;
+            let block = block.get();
+            let node = self.node(&block, block_idx)?;
+            if node.is_leaf() {
+                let Ok(idx) = node.search(key) else {
+                    return Ok(None);
+                };
+                (idx, None)
+            } else {
+                let idx = node.child_index(key);
+                (idx, Some(node.child(idx)))
+            }
+        };
+        let Some(child) = child else {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get_mut(block_idx)?;
+            let mut block = block.get();
+            let mut node = self.node_mut(&mut block, block_idx)?;
+            let cell = node.view().cell(idx);
+            node.remove(idx);
+            return Ok(Some(cell));
+        };
+
+        let cell = self.remove_node(child, key)?;
+        if cell.is_some() && self.is_underfull(child)? {
+            self.rebalance(block_idx, idx)?;
+        }
+        Ok(cell)
+    }
+
+    fn is_underfull(&self, block_idx: u64) -> Result<bool, BPTreeError> {
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(block_idx)?;
+        let block = block.get();
+        let node = self.node(&block, block_idx)?;
+        let capacity = capacity(self.block_size);
+        Ok(capacity - node.free_space() < capacity / 4)
+    }
+
+    /// Merges the `idx`-th child of `father` with a sibling, or redistributes the cells of both
+    /// by size if they do not fit in one node. Redistribution is skipped when the new separator
+    /// does not fit in `father`.
+    fn rebalance(&mut self, father: u64, idx: usize) -> Result<(), BPTreeError> {
+        let freed = {
+            let mut ioc = self.io_context.borrow_mut();
+            let father_block = ioc.get_mut(father)?;
+            let mut father_block = father_block.get();
+            let mut father_node = self.node_mut(&mut father_block, father)?;
+            let num_cells = father_node.view().len();
+            if num_cells == 0 {
+                return Ok(());
+            }
+            let pos = idx.min(num_cells - 1);
+            let left = father_node.view().child(pos);
+            let right = father_node.view().child(pos + 1);
+
+            let left_block = ioc.get_mut(left)?;
+            let mut left_block = left_block.get();
+            let mut left_node = self.node_mut(&mut left_block, left)?;
+            let right_block = ioc.get_mut(right)?;
+            let mut right_block = right_block.get();
+            let mut right_node = self.node_mut(&mut right_block, right)?;
+
+            let is_leaf = left_node.view().is_leaf();
+            let mut cells = left_node.view().cells();
+            if !is_leaf {
+                let separator = father_node.view().key(pos).to_vec();
+                let leftmost = right_node.view().header().leftmost.get();
+                cells.push(Cell::separator(separator, leftmost));
+            }
+            cells.extend(right_node.view().cells());
+
+            if cells.iter().map(Cell::size).sum::<usize>() <= capacity(self.block_size) {
+                left_node.rebuild(&cells);
+                if is_leaf {
+                    let next = right_node.header().next.get();
+                    left_node.header().next = next.into();
+                    if next != u64::MAX {
+                        let next_block = ioc.get_mut(next)?;
+                        let mut next_block = next_block.get();
+                        self.node_mut(&mut next_block, next)?.header().prev = left.into();
+                    }
+                }
+                father_node.remove(pos);
+                Some(right)
+            } else {
+                let mid = if is_leaf {
+                    Self::split_point(&cells)
+                } else {
+                    Self::split_point(&cells).clamp(1, cells.len() - 2)
+                };
+                let separator = Cell::separator(cells[mid].key.clone(), right);
+                let room = father_node.view().free_space() + father_node.view().cell(pos).size();
+                if separator.size() > room {
+                    return Ok(());
+                }
+                father_node.remove(pos);
+                let inserted = father_node.insert(pos, &separator);
+                debug_assert!(inserted);
+
+                left_node.rebuild(&cells[..mid]);
+                if is_leaf {
+                    right_node.rebuild(&cells[mid..]);
+                } else {
+                    right_node.header().leftmost = cells[mid].child().into();
+                    right_node.rebuild(&cells[mid + 1..]);
+                }
+                None
+            }
+        };
+        if let Some(block_idx) = freed {
+            self.allocator.borrow_mut().free(block_idx)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the root while it has no keys left: an internal root hands over to its only
+    /// child, and an empty leaf root leaves the tree empty.
+    fn collapse_root(&mut self) -> Result<(), BPTreeError> {
+        while let Some(root_block) = self.root_block {
+            let (is_leaf, num_cells, leftmost) = {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get(root_block)?;
+                let block = block.get();
+                let node = self.node(&block, root_block)?;
+                (node.is_leaf(), node.len(), node.header().leftmost.get())
+            };
+            if num_cells > 0 {
+                break;
+            }
+            if is_leaf {
+                self.root_block = None;
+                self.first_leaf = u64::MAX;
+                self.height = 0;
+            } else {
+                self.root_block = Some(leftmost);
+                self.height -= 1;
+            }
+            self.allocator.borrow_mut().free(root_block)?;
+        }
+        Ok(())
+    }
+
+    /// Iterates over the entries whose keys fall within `bounds`, in key order.
+    pub fn range(&self, bounds: impl RangeBounds<[u8]>) -> Range<'_, D, C, A> {
+        Range::new(self, bounds)
+    }
+
+    /// Leaf whose key range covers `key`, or `u64::MAX` for an empty tree.
+    fn leaf_for(&self, key: &[u8]) -> Result<u64, BPTreeError> {
+        let Some(mut cur_block) = self.root_block else {
+            return Ok(u64::MAX);
+        };
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let node = self.node(&block, cur_block)?;
+            if node.is_leaf() {
+                return Ok(cur_block);
+            }
+            cur_block = node.child(node.child_index(key));
+        }
+    }
+
+    /// Whether the cell stored for `key` keeps its value in the chain `overflow` points at.
+    fn holds_overflow(&self, key: &[u8], overflow: &OverflowRef) -> Result<bool, BPTreeError> {
+        let leaf = self.leaf_for(key)?;
+        if leaf == u64::MAX {
+            return Ok(false);
+        }
+        let mut ioc = self.io_context.borrow_mut();
+        let block = ioc.get(leaf)?;
+        let block = block.get();
+        let node = self.node(&block, leaf)?;
+        Ok(node.search(key).is_ok_and(|idx| {
+            node.cell(idx)
+                .overflow()
+                .is_some_and(|stored| stored.first == overflow.first)
+        }))
+    }
+
+    fn rightmost_leaf(&self) -> Result<u64, BPTreeError> {
+        let Some(mut cur_block) = self.root_block else {
+            return Ok(u64::MAX);
+        };
+        loop {
+            let mut ioc = self.io_context.borrow_mut();
+            let block = ioc.get(cur_block)?;
+            let block = block.get();
+            let node = self.node(&block, cur_block)?;
+            if node.is_leaf() {
+                return Ok(cur_block);
+            }
+            cur_block = node.child(node.len());
+        }
+    }
+
+    pub fn root_block(&self) -> Option<u64> {
+        self.root_block
+    }
+
+    pub fn first_leaf(&self) -> u64 {
+        self.first_leaf
+    }
+
+    pub fn desc_block(&self) -> Option<u64> {
+        self.desc_block
+    }
+
+    /// Number of keys in the tree.
+    pub fn len(&self) -> u64 {
+        self.num_keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_keys == 0
+    }
+
+    /// Number of levels, counting the leaves; 0 for an empty tree.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    use crate::block_allocator::{BlockAllocateError, test_allocator::setup};
+
+    use super::*;
+
+    fn entry(i: u64) -> (Vec<u8>, Vec<u8>) {
+        let key = format!("{:x}/{}", (i * 7919) % 4001, "k".repeat(i as usize % 50));
+        let val = vec![i as u8; [0, 10, 300, 900, 6000][i as usize % 5]];
+        (key.into_bytes(), val)
+    }
+
+    #[test]
+    fn insert_get_remove() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(8192)?;
+        let mut bptree = VarBPTree::new(iocontext.clone(), allocator.clone());
+        let n = 4000;
+
+        for i in 0..n {
+            let (key, val) = entry(i);
+            bptree.insert(&key, &val)?;
+        }
+        assert_eq!(bptree.len(), n);
+        assert!(bptree.height() > 1);
+        for i in 0..n {
+            let (key, val) = entry(i);
+            assert_eq!(bptree.get(&key)?, Some(val));
+        }
+
+        // Replacing a value swaps its overflow chain for an inline value and back.
+        let (key, _) = entry(4);
+        bptree.insert(&key, b"short")?;
+        assert_eq!(bptree.get(&key)?, Some(b"short".to_vec()));
+        bptree.insert(&key, &entry(4).1)?;
+        assert_eq!(bptree.len(), n);
+
+        for i in (0..n).step_by(2) {
+            let (key, val) = entry(i);
+            assert_eq!(bptree.remove(&key)?, Some(val));
+        }
+        for i in 0..n {
+            let (key, val) = entry(i);
+            assert_eq!(bptree.get(&key)?, (i % 2 == 1).then_some(val));
+        }
+        assert_eq!(bptree.remove(&entry(0).0)?, None);
+        assert_eq!(bptree.len(), n / 2);
+        for i in (1..n).step_by(2) {
+            let (key, _) = entry(i);
+            assert!(bptree.remove(&key)?.is_some());
+        }
+        assert!(bptree.is_empty());
+        assert_eq!(bptree.root_block(), None);
+
+        // Nodes and overflow blocks have all been handed back.
+        let mut free_blocks = 0;
+        while allocator.borrow_mut().alloc().is_ok() {
+            free_blocks += 1;
+        }
+        assert_eq!(free_blocks, 8192 - 2);
+
+        let long_key = vec![0; bptree.max_key_len() + 1];
+        assert!(matches!(
+            bptree.insert(&long_key, b""),
+            Err(BPTreeError::KeyTooLong { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_insert_frees_overflow() -> Result<(), BPTreeError> {
+        let key = |i: u64| format!("{i:04}/{}", "k".repeat(150)).into_bytes();
+        let val = vec![7; 6000];
+        // Disks of different sizes run out at different points: while writing the chain, or
+        // while reserving blocks for a leaf split or a new root.
+        for blocks in 40..56 {
+            let (iocontext, allocator) = setup(blocks)?;
+            let mut bptree = VarBPTree::new(iocontext.clone(), allocator.clone());
+            let mut n = 0;
+            loop {
+                let free = allocator.borrow().free_blocks_count();
+                match bptree.insert(&key(n), &val) {
+                    Ok(()) => n += 1,
+                    Err(e) => {
+                        assert!(matches!(
+                            e,
+                            BPTreeError::AllocateError(BlockAllocateError::NoFreeBlocks)
+                        ));
+                        assert_eq!(allocator.borrow().free_blocks_count(), free);
+                        break;
+                    }
+                }
+            }
+            assert_eq!(bptree.len(), n);
+            assert_eq!(bptree.get(&key(n))?, None);
+            for i in 0..n {
+                assert_eq!(bptree.get(&key(i))?, Some(val.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_range_stops_at_its_end() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(8192)?;
+        let mut bptree = VarBPTree::new(iocontext.clone(), allocator.clone());
+        for i in 0..2000 {
+            let (key, val) = entry(i);
+            bptree.insert(&key, &val)?;
+        }
+
+        let mut leaves = Vec::new();
+        let mut leaf = bptree.first_leaf();
+        while leaf != u64::MAX {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(leaf)?;
+            let block = block.get();
+            let node = bptree.node(&block, leaf)?;
+            leaves.push((leaf, node.key(0).to_vec()));
+            leaf = node.header().next.get();
+        }
+        assert!(leaves.len() > 8);
+
+        // The range covers leaves 2 to 4, and has to look at leaf 5 to see that it ends there.
+        // Every other leaf is unreadable, so reading any of them fails the range.
+        for &(leaf, _) in leaves[..2].iter().chain(&leaves[6..]) {
+            iocontext.borrow_mut().get_mut(leaf)?.get().fill(0);
+        }
+        let bounds = (
+            Bound::Included(&leaves[2].1[..]),
+            Bound::Excluded(&leaves[5].1[..]),
+        );
+        let range = bptree.range(bounds).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(range.first().map(|(key, _)| key), Some(&leaves[2].1));
+        let mut rev = bptree.range(bounds).rev().collect::<Result<Vec<_>, _>>()?;
+        rev.reverse();
+        assert_eq!(rev, range);
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_node_is_corrupted() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(64)?;
+        let mut bptree = VarBPTree::new(iocontext.clone(), allocator);
+        for i in 0..4 {
+            bptree.insert(format!("key{i}").as_bytes(), b"val")?;
+        }
+        let root = bptree.root_block().unwrap();
+        let intact = iocontext.borrow_mut().get(root)?.get().clone();
+        let slot = size_of::<slotted_node::SlottedHeader>();
+        let cell = u32::from_le_bytes(intact[slot..slot + SLOT_SIZE].try_into().unwrap()) as usize;
+
+        let damage = [
+            // Slot pointing past the end of the block.
+            (slot, u32::MAX.to_le_bytes().to_vec()),
+            // Key running past the end of the block.
+            (cell, u16::MAX.to_le_bytes().to_vec()),
+            // Value running past the end of the block.
+            (cell + 4, u32::MAX.to_le_bytes().to_vec()),
+            // More bytes in use than the node has room for.
+            (24, u64::MAX.to_le_bytes().to_vec()),
+        ];
+        for (offset, bytes) in damage {
+            {
+                let mut ioc = iocontext.borrow_mut();
+                let block = ioc.get_mut(root)?;
+                let mut block = block.get();
+                block.copy_from_slice(&intact);
+                block[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            }
+            assert!(matches!(
+                bptree.get(b"key0"),
+                Err(BPTreeError::Corrupted { block }) if block == root
+            ));
+            assert!(matches!(
+                bptree.remove(b"key0"),
+                Err(BPTreeError::Corrupted { block }) if block == root
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_cycle_is_corrupted() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(64)?;
+        let mut bptree = VarBPTree::new(iocontext.clone(), allocator);
+        bptree.insert(b"key", &[7; 6000])?;
+        let root = bptree.root_block().unwrap();
+        let first = {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get(root)?;
+            let block = block.get();
+            bptree
+                .node(&block, root)?
+                .cell(0)
+                .overflow()
+                .unwrap()
+                .first
+                .get()
+        };
+
+        // Point the last block of the chain back at the first one.
+        let mut cur_block = first;
+        loop {
+            let mut ioc = iocontext.borrow_mut();
+            let block = ioc.get_mut(cur_block)?;
+            let mut block = block.get();
+            let next = u64::from_le_bytes(block[..8].try_into().unwrap());
+            if next == u64::MAX {
+                block[..8].copy_from_slice(&first.to_le_bytes());
+                break;
+            }
+            cur_block = next;
+        }
+        assert!(matches!(
+            bptree.get(b"key"),
+            Err(BPTreeError::Corrupted { .. })
+        ));
+        assert!(matches!(
+            bptree.remove(b"key"),
+            Err(BPTreeError::Corrupted { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn range_and_reopen() -> Result<(), BPTreeError> {
+        let (iocontext, allocator) = setup(8192)?;
+        let desc_block = allocator.borrow_mut().alloc()?;
+        let mut bptree = VarBPTree::create(desc_block, iocontext.clone(), allocator.clone())?;
+        let mut expected = BTreeMap::new();
+        for i in 0..2000 {
+            let (key, val) = entry(i);
+            bptree.insert(&key, &val)?;
+            expected.insert(key, val);
+        }
+
+        let all = bptree.range(..).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+        let mut rev = bptree.range(..).rev().collect::<Result<Vec<_>, _>>()?;
+        rev.reverse();
+        assert_eq!(rev, all);
+
+        let bounds = [
+            (Bound::Included(&b"1"[..]), Bound::Excluded(&b"7"[..])),
+            (Bound::Excluded(&b"a/"[..]), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Included(&b"3ff/kkk"[..])),
+        ];
+        for bounds in bounds {
+            let range = bptree.range(bounds).collect::<Result<Vec<_>, _>>()?;
+            let owned = (bounds.0.map(<[u8]>::to_vec), bounds.1.map(<[u8]>::to_vec));
+            let expected_range = expected
+                .range(owned.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(range, expected_range);
+
+            let mut rev = bptree.range(bounds).rev().collect::<Result<Vec<_>, _>>()?;
+            rev.reverse();
+            assert_eq!(rev, expected_range);
+        }
+
+        iocontext.borrow_mut().flush()?;
+        let bptree = VarBPTree::open(desc_block, iocontext.clone(), allocator.clone())?;
+        assert_eq!(bptree.len(), 2000);
+        assert_eq!(bptree.range(..).collect::<Result<Vec<_>, _>>()?, all);
+        assert!(matches!(
+            VarBPTree::open(desc_block + 1, iocontext, allocator),
+            Err(BPTreeError::BadDescriptor { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::io_context::IOContext;
+use crate::utils::bp_tree::BPTreeError;
+use crate::utils::cache::Cache;
+use crate::utils::var_bp_tree::VarBPTree;
+use crate::utils::var_bp_tree::slotted_node::{OverflowHeader, OverflowRef};
+
+/// Number of blocks `write_overflow` spreads the value behind `overflow` over, or an error if
+/// that is more than the disk holds.
+fn chain_len<D, C>(ioc: &IOContext<D, C>, overflow: &OverflowRef) -> Result<u64, BPTreeError>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    let block_size = ioc.get_disk_block_size();
+    let blocks = overflow
+        .len
+        .get()
+        .div_ceil(block_size - size_of::<OverflowHeader>() as u64);
+    if blocks > ioc.get_disk_capacity() / block_size {
+        return Err(BPTreeError::Corrupted {
+            block: overflow.first.get(),
+        });
+    }
+    Ok(blocks)
+}
+
+/// Reads back a value stored by `VarBPTree::write_overflow`. A chain longer than the value
+/// needs, as a cycle would make it, is reported as corrupted.
+pub(super) fn read_overflow<D, C>(
+    ioc: &mut IOContext<D, C>,
+    overflow: &OverflowRef,
+) -> Result<Vec<u8>, BPTreeError>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    let max_blocks = chain_len(ioc, overflow)?;
+    let mut val = Vec::with_capacity(overflow.len.get() as usize);
+    let mut cur_block = overflow.first.get();
+    let mut blocks = 0;
+    while cur_block != u64::MAX {
+        blocks += 1;
+        if blocks > max_blocks {
+            return Err(BPTreeError::Corrupted { block: cur_block });
+        }
+        let block = ioc.get(cur_block)?;
+        let block = block.get();
+        let (header, data) = OverflowHeader::ref_from_prefix(&block)
+            .map_err(|_| BPTreeError::Corrupted { block: cur_block })?;
+        let len = header.len.get() as usize;
+        if len > data.len() || (val.len() + len) as u64 > overflow.len.get() {
+            return Err(BPTreeError::Corrupted { block: cur_block });
+        }
+        val.extend_from_slice(&data[..len]);
+        cur_block = header.next.get();
+    }
+    if val.len() as u64 != overflow.len.get() {
+        return Err(BPTreeError::Corrupted {
+            block: overflow.first.get(),
+        });
+    }
+    Ok(val)
+}
+
+impl<D, C, A> VarBPTree<D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+{
+    /// Spreads `val` over a chain of freshly allocated blocks.
+    pub(super) fn write_overflow(&mut self, val: &[u8]) -> Result<OverflowRef, BPTreeError> {
+        let chunk = self.block_size - size_of::<OverflowHeader>();
+        let mut blocks = Vec::with_capacity(val.len().div_ceil(chunk));
+        for _ in 0..val.len().div_ceil(chunk) {
+            let block_idx = match blocks.last() {
+                Some(&prev) => self.allocator.borrow_mut().alloc_near(prev),
+                None => self.allocator.borrow_mut().alloc(),
+            };
+            match block_idx {
+                Ok(block_idx) => blocks.push(block_idx),
+                Err(e) => {
+                    for block_idx in blocks {
+                        self.allocator.borrow_mut().free(block_idx)?;
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let mut ioc = self.io_context.borrow_mut();
+        for (i, data) in val.chunks(chunk).enumerate() {
+            let block = ioc.get_mut(blocks[i])?;
+            let mut block = block.get();
+            let header = OverflowHeader {
+                next: blocks.get(i + 1).copied().unwrap_or(u64::MAX).into(),
+                len: (data.len() as u64).into(),
+            };
+            header
+                .write_to_prefix(&mut block)
+                .map_err(|_| BPTreeError::Corrupted { block: blocks[i] })?;
+            block[size_of::<OverflowHeader>()..][..data.len()].copy_from_slice(data);
+        }
+        Ok(OverflowRef {
+            first: blocks.first().copied().unwrap_or(u64::MAX).into(),
+            len: (val.len() as u64).into(),
+        })
+    }
+
+    /// Hands every block of an overflow chain back to the allocator, stopping with an error
+    /// where the chain runs on past the blocks the value needs.
+    pub(super) fn free_overflow(&mut self, overflow: &OverflowRef) -> Result<(), BPTreeError> {
+        let max_blocks = chain_len(&self.io_context.borrow(), overflow)?;
+        let mut cur_block = overflow.first.get();
+        let mut blocks = 0;
+        while cur_block != u64::MAX {
+            blocks += 1;
+            if blocks > max_blocks {
+                return Err(BPTreeError::Corrupted { block: cur_block });
+            }
+            let next = {
+                let mut ioc = self.io_context.borrow_mut();
+                let block = ioc.get(cur_block)?;
+                let block = block.get();
+                OverflowHeader::ref_from_prefix(&block)
+                    .map_err(|_| BPTreeError::Corrupted { block: cur_block })?
+                    .0
+                    .next
+                    .get()
+            };
+            self.allocator.borrow_mut().free(cur_block)?;
+            cur_block = next;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+use std::rc::Rc;
+
+use crate::block_allocator::BlockAllocator;
+use crate::block_device::BlockDevice;
+use crate::utils::bp_tree::BPTreeError;
+use crate::utils::cache::Cache;
+use crate::utils::var_bp_tree::VarBPTree;
+use crate::utils::var_bp_tree::overflow::read_overflow;
+use crate::utils::var_bp_tree::slotted_node::Cell;
+
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// What `Range` takes from a leaf: the cells within the bounds, and the leaf's own first and
+/// last key and sibling links, which decide whether the walk goes on.
+struct LoadedLeaf {
+    cells: Vec<Cell>,
+    first_key: Option<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
+    next: u64,
+    prev: u64,
+}
+
+/// Iterator over the `(key, val)` pairs of a `VarBPTree` within a key range, in key order.
+///
+/// Works like `bp_tree::Range`: one leaf per end is buffered, the front follows `next` links,
+/// the back follows `prev` links, and each yielded key tightens the opposite bound. Overflowed
+/// values are only read back as their entry is yielded.
+pub struct Range<'a, D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+{
+    tree: &'a VarBPTree<D, C, A>,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    front: VecDeque<Cell>,
+    back: VecDeque<Cell>,
+    next_leaf: Option<u64>,
+    prev_leaf: Option<u64>,
+    done: bool,
+}
+
+impl<'a, D, C, A> Range<'a, D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+{
+    pub(super) fn new(tree: &'a VarBPTree<D, C, A>, bounds: impl RangeBounds<[u8]>) -> Self {
+        Self {
+            tree,
+            lower: bounds.start_bound().map(<[u8]>::to_vec),
+            upper: bounds.end_bound().map(<[u8]>::to_vec),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            next_leaf: None,
+            prev_leaf: None,
+            done: tree.root_block.is_none(),
+        }
+    }
+
+    fn in_bounds(&self, key: &[u8]) -> bool {
+        (
+            self.lower.as_ref().map(Vec::as_slice),
+            self.upper.as_ref().map(Vec::as_slice),
+        )
+            .contains(key)
+    }
+
+    /// Reads the cells of `leaf` that fall within the bounds.
+    fn load_leaf(&self, leaf: u64) -> Result<LoadedLeaf, BPTreeError> {
+        let mut ioc = self.tree.io_context.borrow_mut();
+        let block = ioc.get(leaf)?;
+        let block = block.get();
+        let node = self.tree.node(&block, leaf)?;
+        Ok(LoadedLeaf {
+            cells: (0..node.len())
+                .filter(|&idx| self.in_bounds(node.key(idx)))
+                .map(|idx| node.cell(idx))
+                .collect(),
+            first_key: (node.len() > 0).then(|| node.key(0).to_vec()),
+            last_key: node.len().checked_sub(1).map(|idx| node.key(idx).to_vec()),
+            next: node.header().next.get(),
+            prev: node.header().prev.get(),
+        })
+    }
+
+    /// Turns a buffered cell into its entry, reading back an overflowed value.
+    fn entry(&self, cell: Cell) -> Result<Entry, BPTreeError> {
+        let val = match cell.overflow() {
+            Some(overflow) => read_overflow(&mut self.tree.io_context.borrow_mut(), &overflow)?,
+            None => cell.val,
+        };
+        Ok((cell.key, val))
+    }
+
+    /// Reads the next leaf into the front buffer, returning `false` once the chain ends.
+    fn load_front(&mut self) -> Result<bool, BPTreeError> {
+        let leaf = match self.next_leaf {
+            Some(leaf) => leaf,
+            None => match &self.lower {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.leaf_for(key)?,
+                Bound::Unbounded => self.tree.first_leaf,
+            },
+        };
+        if leaf == u64::MAX {
+            return Ok(false);
+        }
+
+        let loaded = self.load_leaf(leaf)?;
+        let past_end = loaded.last_key.is_some_and(|last| match &self.upper {
+            Bound::Included(end) | Bound::Excluded(end) => &last >= end,
+            Bound::Unbounded => false,
+        });
+        self.front.extend(loaded.cells);
+        self.next_leaf = Some(if past_end { u64::MAX } else { loaded.next });
+        Ok(true)
+    }
+
+    /// Reads the previous leaf into the back buffer, returning `false` once the chain ends.
+    fn load_back(&mut self) -> Result<bool, BPTreeError> {
+        let leaf = match self.prev_leaf {
+            Some(leaf) => leaf,
+            None => match &self.upper {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.leaf_for(key)?,
+                Bound::Unbounded => self.tree.rightmost_leaf()?,
+            },
+        };
+        if leaf == u64::MAX {
+            return Ok(false);
+        }
+
+        let loaded = self.load_leaf(leaf)?;
+        let past_start = loaded.first_key.is_some_and(|first| match &self.lower {
+            Bound::Included(start) | Bound::Excluded(start) => &first <= start,
+            Bound::Unbounded => false,
+        });
+        self.back.extend(loaded.cells.into_iter().rev());
+        self.prev_leaf = Some(if past_start { u64::MAX } else { loaded.prev });
+        Ok(true)
+    }
+
+    fn finish(&mut self, e: Option<BPTreeError>) -> Option<<Self as Iterator>::Item> {
+        self.done = true;
+        self.front.clear();
+        self.back.clear();
+        e.map(Err)
+    }
+}
+
+impl<D, C, A> Iterator for Range<'_, D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+{
+    type Item = Result<Entry, BPTreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some(cell) = self.front.pop_front() {
+                if !self.in_bounds(&cell.key) {
+                    return self.finish(None);
+                }
+                self.lower = Bound::Excluded(cell.key.clone());
+                return match self.entry(cell) {
+                    Ok(entry) => Some(Ok(entry)),
+                    Err(e) => self.finish(Some(e)),
+                };
+            }
+            match self.load_front() {
+                Ok(true) => {}
+                Ok(false) => return self.finish(None),
+                Err(e) => return self.finish(Some(e)),
+            }
+        }
+        None
+    }
+}
+
+impl<D, C, A> DoubleEndedIterator for Range<'_, D, C, A>
+where
+    D: BlockDevice,
+    C: Cache<u64, Rc<RefCell<Vec<u8>>>>,
+    A: BlockAllocator,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some(cell) = self.back.pop_front() {
+                if !self.in_bounds(&cell.key) {
+                    return self.finish(None);
+                }
+                self.upper = Bound::Excluded(cell.key.clone());
+                return match self.entry(cell) {
+                    Ok(entry) => Some(Ok(entry)),
+                    Err(e) => self.finish(Some(e)),
+                };
+            }
+            match self.load_back() {
+                Ok(true) => {}
+                Ok(false) => return self.finish(None),
+                Err(e) => return self.finish(Some(e)),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,350 @@
+use std::cmp::Ordering;
+
+use zerocopy::byteorder::little_endian::*;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+/// Header of a slotted node. The slot array follows it and grows up; cells are packed at the
+/// end of the block and grow down.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
+pub struct SlottedHeader {
+    pub is_leaf: u8,
+    _padding: [u8; 7],
+    pub num_cells: U64,
+    /// Offset of the lowest cell in the block.
+    pub cell_start: U64,
+    /// Bytes taken by live cells and their slots.
+    pub used: U64,
+    pub next: U64,
+    pub prev: U64,
+    /// Internal nodes only: child holding the keys below the first separator.
+    pub leftmost: U64,
+}
+
+/// Header in front of every cell's key and value bytes.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
+pub struct CellHeader {
+    pub key_len: U16,
+    pub flags: U16,
+    pub val_len: U32,
+}
+
+/// Stands in for a value that lives in a chain of overflow blocks.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
+pub struct OverflowRef {
+    pub first: U64,
+    pub len: U64,
+}
+
+/// Header of an overflow block; the rest of the block holds value bytes.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
+pub struct OverflowHeader {
+    pub next: U64,
+    pub len: U64,
+}
+
+pub const VAR_TREE_MAGIC: u64 = 0x0056_4152_5452_4545;
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Unaligned, Immutable, KnownLayout, Debug)]
+pub struct VarTreeDescriptor {
+    pub magic: U64,
+    pub root: U64,
+    pub first_leaf: U64,
+    pub height: U64,
+    pub num_keys: U64,
+    pub block_size: U64,
+}
+
+/// The cell's value is an `OverflowRef`.
+pub const CELL_OVERFLOW: u16 = 1;
+
+pub const SLOT_SIZE: usize = size_of::<U32>();
+
+/// Owned copy of a cell, used while nodes are split, merged or rebuilt.
+#[derive(Clone, Debug)]
+pub struct Cell {
+    pub key: Vec<u8>,
+    pub flags: u16,
+    pub val: Vec<u8>,
+}
+
+impl Cell {
+    /// Separator pointing at `child`, as stored in internal nodes.
+    pub fn separator(key: Vec<u8>, child: u64) -> Self {
+        Self {
+            key,
+            flags: 0,
+            val: child.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Bytes the cell takes in a node, slot included.
+    pub fn size(&self) -> usize {
+        SLOT_SIZE + size_of::<CellHeader>() + self.key.len() + self.val.len()
+    }
+
+    pub fn child(&self) -> u64 {
+        U64::read_from_bytes(&self.val).map_or(u64::MAX, |child| child.get())
+    }
+
+    pub fn overflow(&self) -> Option<OverflowRef> {
+        if self.flags & CELL_OVERFLOW == 0 {
+            return None;
+        }
+        OverflowRef::read_from_bytes(&self.val).ok()
+    }
+}
+
+/// Bytes available to slots and cells in a block of `block_size` bytes.
+pub fn capacity(block_size: usize) -> usize {
+    block_size - size_of::<SlottedHeader>()
+}
+
+pub struct SlottedNode<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SlottedNode<'a> {
+    /// Wraps `bytes`, returning `None` if the header does not describe a well-formed node.
+    ///
+    /// Every slot has to point at a cell whose key and value lie between `cell_start` and the
+    /// end of the block, and `used` has to add up to the cells and slots, so the accessors
+    /// below can index and subtract without checking again.
+    pub fn get_from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        let (header, _) = SlottedHeader::ref_from_prefix(bytes).ok()?;
+        let num_cells = usize::try_from(header.num_cells.get()).ok()?;
+        let slots_end =
+            size_of::<SlottedHeader>().checked_add(num_cells.checked_mul(SLOT_SIZE)?)?;
+        let cell_start = usize::try_from(header.cell_start.get()).ok()?;
+        if slots_end > cell_start || cell_start > bytes.len() {
+            return None;
+        }
+        let mut used = 0;
+        for slot in bytes[size_of::<SlottedHeader>()..slots_end].chunks_exact(SLOT_SIZE) {
+            let offset = U32::read_from_bytes(slot).ok()?.get() as usize;
+            if offset < cell_start {
+                return None;
+            }
+            let (cell, _) = CellHeader::ref_from_prefix(bytes.get(offset..)?).ok()?;
+            let cell_end = offset
+                + size_of::<CellHeader>()
+                + cell.key_len.get() as usize
+                + cell.val_len.get() as usize;
+            if cell_end > bytes.len() {
+                return None;
+            }
+            used += SLOT_SIZE + cell_end - offset;
+        }
+        if used as u64 != header.used.get() || used > capacity(bytes.len()) {
+            return None;
+        }
+        Some(Self { bytes })
+    }
+
+    pub fn header(&self) -> &'a SlottedHeader {
+        SlottedHeader::ref_from_prefix(self.bytes).unwrap().0
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.header().is_leaf == 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.header().num_cells.get() as usize
+    }
+
+    pub fn free_space(&self) -> usize {
+        capacity(self.bytes.len()) - self.header().used.get() as usize
+    }
+
+    fn slot(&self, idx: usize) -> usize {
+        let offset = size_of::<SlottedHeader>() + idx * SLOT_SIZE;
+        U32::read_from_bytes(&self.bytes[offset..offset + SLOT_SIZE])
+            .unwrap()
+            .get() as usize
+    }
+
+    fn cell_header(&self, idx: usize) -> (usize, &'a CellHeader) {
+        let offset = self.slot(idx);
+        let (header, _) = CellHeader::ref_from_prefix(&self.bytes[offset..]).unwrap();
+        (offset + size_of::<CellHeader>(), header)
+    }
+
+    pub fn key(&self, idx: usize) -> &'a [u8] {
+        let (offset, header) = self.cell_header(idx);
+        &self.bytes[offset..offset + header.key_len.get() as usize]
+    }
+
+    pub fn flags(&self, idx: usize) -> u16 {
+        self.cell_header(idx).1.flags.get()
+    }
+
+    pub fn val(&self, idx: usize) -> &'a [u8] {
+        let (offset, header) = self.cell_header(idx);
+        let offset = offset + header.key_len.get() as usize;
+        &self.bytes[offset..offset + header.val_len.get() as usize]
+    }
+
+    pub fn cell(&self, idx: usize) -> Cell {
+        Cell {
+            key: self.key(idx).to_vec(),
+            flags: self.flags(idx),
+            val: self.val(idx).to_vec(),
+        }
+    }
+
+    pub fn cells(&self) -> Vec<Cell> {
+        (0..self.len()).map(|idx| self.cell(idx)).collect()
+    }
+
+    /// Finds `key` among the cells, as `slice::binary_search` does.
+    pub fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.key(mid).cmp(key) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+
+    /// Internal nodes only: index of the child whose subtree holds `key`.
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        match self.search(key) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        }
+    }
+
+    /// Internal nodes only: the `idx`-th child, counting `leftmost` as the first one.
+    pub fn child(&self, idx: usize) -> u64 {
+        if idx == 0 {
+            return self.header().leftmost.get();
+        }
+        U64::read_from_bytes(self.val(idx - 1)).map_or(u64::MAX, |child| child.get())
+    }
+}
+
+pub struct SlottedNodeMut<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> SlottedNodeMut<'a> {
+    pub fn get_from_bytes(bytes: &'a mut [u8]) -> Option<Self> {
+        SlottedNode::get_from_bytes(bytes)?;
+        Some(Self { bytes })
+    }
+
+    /// Formats `bytes` as an empty node, unlinked from any sibling.
+    pub fn init(bytes: &'a mut [u8], is_leaf: bool) -> Self {
+        let len = bytes.len() as u64;
+        let (header, _) = SlottedHeader::mut_from_prefix(bytes).unwrap();
+        *header = SlottedHeader {
+            is_leaf: if is_leaf { 1 } else { 0 },
+            _padding: [0; 7],
+            num_cells: 0.into(),
+            cell_start: len.into(),
+            used: 0.into(),
+            next: U64::MAX_VALUE,
+            prev: U64::MAX_VALUE,
+            leftmost: U64::MAX_VALUE,
+        };
+        Self { bytes }
+    }
+
+    pub fn view(&self) -> SlottedNode<'_> {
+        SlottedNode { bytes: self.bytes }
+    }
+
+    pub fn header(&mut self) -> &mut SlottedHeader {
+        SlottedHeader::mut_from_prefix(self.bytes).unwrap().0
+    }
+
+    fn set_slot(&mut self, idx: usize, offset: usize) {
+        let start = size_of::<SlottedHeader>() + idx * SLOT_SIZE;
+        U32::new(offset as u32)
+            .write_to(&mut self.bytes[start..start + SLOT_SIZE])
+            .unwrap();
+    }
+
+    /// Inserts `cell` as the `idx`-th one, returning `false` if the node has no room for it.
+    pub fn insert(&mut self, idx: usize, cell: &Cell) -> bool {
+        if cell.size() > self.view().free_space() {
+            return false;
+        }
+        let num_cells = self.view().len();
+        let slots_end = size_of::<SlottedHeader>() + (num_cells + 1) * SLOT_SIZE;
+        let body = cell.size() - SLOT_SIZE;
+        if (self.header().cell_start.get() as usize) < slots_end + body {
+            self.compact();
+        }
+
+        let offset = self.header().cell_start.get() as usize - body;
+        let header = CellHeader {
+            key_len: (cell.key.len() as u16).into(),
+            flags: cell.flags.into(),
+            val_len: (cell.val.len() as u32).into(),
+        };
+        let key_start = offset + size_of::<CellHeader>();
+        header.write_to(&mut self.bytes[offset..key_start]).unwrap();
+        self.bytes[key_start..key_start + cell.key.len()].copy_from_slice(&cell.key);
+        self.bytes[key_start + cell.key.len()..offset + body].copy_from_slice(&cell.val);
+
+        let slots = size_of::<SlottedHeader>() + idx * SLOT_SIZE;
+        self.bytes.copy_within(
+            slots..size_of::<SlottedHeader>() + num_cells * SLOT_SIZE,
+            slots + SLOT_SIZE,
+        );
+        self.set_slot(idx, offset);
+
+        let header = self.header();
+        header.cell_start = (offset as u64).into();
+        header.num_cells += 1;
+        header.used += cell.size() as u64;
+        true
+    }
+
+    /// Drops the `idx`-th cell. Its bytes are reclaimed by the next compaction.
+    pub fn remove(&mut self, idx: usize) {
+        let size = self.view().cell(idx).size();
+        let num_cells = self.view().len();
+        let slots = size_of::<SlottedHeader>() + idx * SLOT_SIZE;
+        self.bytes.copy_within(
+            slots + SLOT_SIZE..size_of::<SlottedHeader>() + num_cells * SLOT_SIZE,
+            slots,
+        );
+        let len = self.bytes.len() as u64;
+        let header = self.header();
+        header.num_cells -= 1;
+        header.used -= size as u64;
+        if header.num_cells.get() == 0 {
+            header.cell_start = len.into();
+        }
+    }
+
+    /// Replaces every cell with `cells`, keeping the sibling links and `leftmost`.
+    pub fn rebuild(&mut self, cells: &[Cell]) {
+        let len = self.bytes.len() as u64;
+        let header = self.header();
+        header.num_cells = 0.into();
+        header.cell_start = len.into();
+        header.used = 0.into();
+        for (idx, cell) in cells.iter().enumerate() {
+            let inserted = self.insert(idx, cell);
+            debug_assert!(inserted);
+        }
+    }
+
+    /// Packs the cells against the end of the block, so all free space is contiguous.
+    fn compact(&mut self) {
+        let cells = self.view().cells();
+        self.rebuild(&cells);
+    }
+}